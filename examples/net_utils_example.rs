@@ -18,7 +18,7 @@ fn main() {
     //set host to
     cfg.server = Some("google.com".to_string());
    // cfg.use_ssl = Some(true);
-    let  pool = poolmgr::ConnectionPool::new(2, 5, true, &cfg);
+    let  pool = poolmgr::ConnectionPool::new(2, 5, true, &cfg).unwrap();
     let pool_shared = Arc::new(pool);
     for _ in 0u32..2 {
             let pool = pool_shared.clone();
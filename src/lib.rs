@@ -10,6 +10,10 @@
 
 //! Net-utils library provides a configurable TCP/SSL client connection pool
 //! A simple, connection pool library.
+//!
+//! The TLS backend is picked at compile time: the `ssl` feature uses OpenSSL and the `rustls`
+//! feature uses rustls. If both are enabled, e.g. with `--all-features`, OpenSSL is used and the
+//! rustls code is not compiled.
 #![crate_name = "net_utils"]
 #![crate_type = "lib"]
 #![warn(missing_docs)]
@@ -17,9 +21,17 @@
 #![allow(unused_must_use)]
 #[cfg(feature = "ssl")]
 extern crate openssl;
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+extern crate rustls;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async-ssl")]
+extern crate tokio_openssl;
 
 #[macro_use]
 extern crate log;
+extern crate libc;
+extern crate socket2;
 extern crate time;
 extern crate uuid;
 /// module net provides the TCP/SSL connection and connection pool functionality
@@ -0,0 +1,330 @@
+//! Async client connection over tokio.  It supports unsecured and secured(SSL) connection.
+//! SSL requires the `async-ssl` feature.
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+#[cfg(feature = "async-ssl")]
+use tokio_openssl::SslStream;
+use uuid::Uuid;
+
+use crate::net::config;
+use crate::net::proxy;
+use crate::net::proxy_protocol::{self, ProxyHeader, ProxyProtocolVersion};
+use crate::net::socket;
+#[cfg(feature = "async-ssl")]
+use crate::net::conn;
+#[cfg(feature = "async-ssl")]
+use crate::net::tls::{self, TlsInfo};
+
+/// An async Connection object. Same as `conn::Connection` but all IO is done with tokio
+pub struct AsyncConnection {
+    id: String,
+    /// Buffered NetStream (TCP/SSL) for reading and writing
+    pub stream: BufStream<AsyncNetStream>,
+    /// Config for connection
+    config: config::Config,
+    /// Address the connection was made to. None for Unix domain sockets
+    remote_addr: Option<SocketAddr>,
+}
+
+impl AsyncConnection {
+    /// new function to create default AsyncConnection object
+    fn new(
+        stream: AsyncNetStream,
+        config: &config::Config,
+        remote_addr: Option<SocketAddr>,
+    ) -> AsyncConnection {
+        AsyncConnection {
+            id: Uuid::new_v4().to_urn_string(),
+            stream: BufStream::new(stream),
+            config: config.clone(),
+            remote_addr,
+        }
+    }
+
+    /// Creates a  TCP/SSL connection to the specified server.
+    pub async fn connect(config: &config::Config) -> Result<AsyncConnection> {
+        // the hook works on blocking connections
+        if config.starttls.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "starttls is not supported by async connections",
+            ));
+        }
+        if config.use_ssl.unwrap_or(false) {
+            AsyncConnection::connect_ssl_internal(config).await
+        } else {
+            AsyncConnection::connect_internal(config).await
+        }
+    }
+
+    /// Creates a TCP/SSL connection which announces `header` with the PROXY protocol.
+    /// The version is `Config::proxy_protocol`, v1 if not set
+    pub async fn connect_with_proxy_header(
+        config: &config::Config,
+        header: ProxyHeader,
+    ) -> Result<AsyncConnection> {
+        let mut config = config.clone();
+        config.proxy_protocol = Some(config.proxy_protocol.unwrap_or(ProxyProtocolVersion::V1));
+        config.proxy_protocol_header = Some(header);
+        AsyncConnection::connect(&config).await
+    }
+
+    /// Creates a new TCP/SSL connection with the same config
+    pub async fn reconnect(&self) -> Result<AsyncConnection> {
+        AsyncConnection::connect(&self.config).await
+    }
+
+    /// Get the connection id
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// The resolved server address which won the connection race, the proxy address if
+    /// `Config::proxy` is set. None for Unix domain sockets
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// The PROXY protocol header given for this connection, if any
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.config.proxy_protocol_header.as_ref()
+    }
+
+    /// Details of the TLS session: version, cipher, the server's certificates, ALPN, resumption
+    /// and the OCSP staple. None for unsecured connections
+    #[cfg(feature = "async-ssl")]
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match *self.stream.get_ref() {
+            AsyncNetStream::SslTcpStream(ref ssl) => Some(TlsInfo::from_ssl(ssl.ssl())),
+            AsyncNetStream::SslUnixStream(ref ssl) => Some(TlsInfo::from_ssl(ssl.ssl())),
+            _ => None,
+        }
+    }
+
+    /// The ALPN protocol the server selected. None for unsecured connections or if no protocol
+    /// was negotiated
+    #[cfg(feature = "async-ssl")]
+    pub fn negotiated_protocol(&self) -> Option<Vec<u8>> {
+        let ssl = match *self.stream.get_ref() {
+            AsyncNetStream::SslTcpStream(ref ssl) => ssl.ssl(),
+            AsyncNetStream::SslUnixStream(ref ssl) => ssl.ssl(),
+            _ => return None,
+        };
+        ssl.selected_alpn_protocol().map(|p| p.to_vec())
+    }
+
+    /// Was the TLS session resumed from `Config::tls_session_cache`. False for unsecured
+    /// connections
+    #[cfg(feature = "async-ssl")]
+    pub fn session_reused(&self) -> bool {
+        match *self.stream.get_ref() {
+            AsyncNetStream::SslTcpStream(ref ssl) => ssl.ssl().session_reused(),
+            AsyncNetStream::SslUnixStream(ref ssl) => ssl.ssl().session_reused(),
+            _ => false,
+        }
+    }
+
+    /// Is Valid connection
+    pub fn is_valid(&self) -> bool {
+        match *self.stream.get_ref() {
+            AsyncNetStream::UnsecuredTcpStream(ref tcp) => tcp.peer_addr().is_ok(),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslTcpStream(ref ssl) => ssl.get_ref().peer_addr().is_ok(),
+            AsyncNetStream::UnixStream(ref unix) => unix.peer_addr().is_ok(),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslUnixStream(ref ssl) => ssl.get_ref().peer_addr().is_ok(),
+        }
+    }
+
+    /// Creates a TCP connection.
+    async fn connect_internal(config: &config::Config) -> Result<AsyncConnection> {
+        if let Some(path) = socket::unix_path(config) {
+            info!("Connecting to unix:{}", path.display());
+            let mut socket = socket::connect_unix_async(config, &path).await?;
+            send_proxy_header(config, &mut socket, None).await?;
+            return Ok(AsyncConnection::new(
+                AsyncNetStream::UnixStream(socket),
+                config,
+                None,
+            ));
+        }
+        let host: &str = &config.server.clone().unwrap();
+        let port = config.port.unwrap();
+        info!("Connecting to server {}:{}", host, port);
+        let (mut socket, remote_addr) = connect_tcp(config).await?;
+        let local_addr = socket.local_addr()?;
+        send_proxy_header(config, &mut socket, Some((local_addr, remote_addr))).await?;
+        Ok(AsyncConnection::new(
+            AsyncNetStream::UnsecuredTcpStream(socket),
+            config,
+            Some(remote_addr),
+        ))
+    }
+
+    /// Fails because async SSL support was not included at compilation.
+    #[cfg(not(feature = "async-ssl"))]
+    async fn connect_ssl_internal(config: &config::Config) -> Result<AsyncConnection> {
+        Err(Error::other(format!(
+            "Cannot connect to {}:{} over SSL without compiling with async-ssl support.",
+            config.server.clone().unwrap(),
+            config.port.unwrap()
+        )))
+    }
+
+    /// Creates a  TCP (or Unix domain socket) connection over SSL.
+    #[cfg(feature = "async-ssl")]
+    async fn connect_ssl_internal(config: &config::Config) -> Result<AsyncConnection> {
+        if let Some(path) = socket::unix_path(config) {
+            info!("Connecting to unix:{}", path.display());
+            let mut socket = socket::connect_unix_async(config, &path).await?;
+            send_proxy_header(config, &mut socket, None).await?;
+            let stream = ssl_handshake(config, socket).await?;
+            return Ok(AsyncConnection::new(
+                AsyncNetStream::SslUnixStream(stream),
+                config,
+                None,
+            ));
+        }
+        let host: &str = &config.server.clone().unwrap();
+        let port = config.port.unwrap();
+        info!("Connecting to server {}:{}", host, port);
+
+        let (mut socket, remote_addr) = connect_tcp(config).await?;
+        let local_addr = socket.local_addr()?;
+        send_proxy_header(config, &mut socket, Some((local_addr, remote_addr))).await?;
+        let stream = ssl_handshake(config, socket).await?;
+        Ok(AsyncConnection::new(
+            AsyncNetStream::SslTcpStream(stream),
+            config,
+            Some(remote_addr),
+        ))
+    }
+}
+
+/// Sends the PROXY protocol header if `Config::proxy_protocol` is set
+async fn send_proxy_header<W: AsyncWrite + Unpin>(
+    config: &config::Config,
+    stream: &mut W,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+) -> Result<()> {
+    if let Some(version) = config.proxy_protocol {
+        let header = config.proxy_protocol_header.as_ref();
+        stream.write_all(&proxy_protocol::header_bytes(version, header, addrs)?).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+/// Connects to the server directly or through `Config::proxy`.  The proxy handshake runs on the
+/// blocking thread pool
+async fn connect_tcp(config: &config::Config) -> Result<(TcpStream, SocketAddr)> {
+    if config.proxy.is_none() {
+        return socket::connect_async(config).await;
+    }
+    let config = config.clone();
+    let (stream, addr) = tokio::task::spawn_blocking(move || {
+        proxy::connect(&config, config.proxy.as_ref().unwrap())
+    }).await
+        .map_err(Error::other)??;
+    stream.set_nonblocking(true)?;
+    Ok((TcpStream::from_std(stream)?, addr))
+}
+
+/// Runs the client SSL handshake over a connected socket
+#[cfg(feature = "async-ssl")]
+async fn ssl_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    config: &config::Config,
+    socket: S,
+) -> Result<SslStream<S>> {
+    let ssl = conn::ssl_configure(config)?
+        .into_ssl(&conn::tls_server_name(config))
+        .map_err(ssl_error)?;
+    let mut stream = SslStream::new(ssl, socket).map_err(ssl_error)?;
+    if let Err(e) = Pin::new(&mut stream).connect().await {
+        if let Some(e) = conn::pin_mismatch(config, stream.ssl()) {
+            return Err(e);
+        }
+        return Err(Error::other(format!("An SSL error occurred. ({})", e)));
+    }
+    tls::check_alpn(config, stream.ssl().selected_alpn_protocol())?;
+    Ok(stream)
+}
+
+/// Converts an SSL error stack into an io::Error
+#[cfg(feature = "async-ssl")]
+fn ssl_error(e: openssl::error::ErrorStack) -> Error {
+    Error::other(format!("An SSL error occurred. ({})", e))
+}
+
+/// An abstraction over different async networked streams.
+pub enum AsyncNetStream {
+    /// An unsecured TcpStream.
+    UnsecuredTcpStream(TcpStream),
+    /// An SSL-secured TcpStream.
+    /// This is only available when compiled with async-ssl support.
+    #[cfg(feature = "async-ssl")]
+    SslTcpStream(SslStream<TcpStream>),
+    /// An unsecured Unix domain socket.
+    UnixStream(UnixStream),
+    /// An SSL-secured Unix domain socket.
+    /// This is only available when compiled with async-ssl support.
+    #[cfg(feature = "async-ssl")]
+    SslUnixStream(SslStream<UnixStream>),
+}
+
+impl AsyncRead for AsyncNetStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        match self.get_mut() {
+            AsyncNetStream::UnsecuredTcpStream(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslTcpStream(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            AsyncNetStream::UnixStream(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslUnixStream(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncNetStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            AsyncNetStream::UnsecuredTcpStream(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslTcpStream(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+            AsyncNetStream::UnixStream(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslUnixStream(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            AsyncNetStream::UnsecuredTcpStream(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslTcpStream(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            AsyncNetStream::UnixStream(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslUnixStream(ref mut stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            AsyncNetStream::UnsecuredTcpStream(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslTcpStream(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
+            AsyncNetStream::UnixStream(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "async-ssl")]
+            AsyncNetStream::SslUnixStream(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
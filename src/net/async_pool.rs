@@ -0,0 +1,792 @@
+//! Async Connection Pool.  The policies of `poolmgr::ConnectionPool`, shared through the same
+//! pool core, for tokio based services.  Validation runs `Config::async_validation_probe`, the
+//! blocking `Config::validation_probe` can not be used with it
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use crate::net::async_conn::AsyncConnection;
+use crate::net::config;
+use crate::net::config::ValidationMode;
+use crate::net::leak;
+use crate::net::pool_core::{PoolCore, PooledConnection, Slot, Waiter};
+use crate::net::poolmgr::{PoolStats, Priority};
+use crate::net::proxy_protocol::ProxyHeader;
+use crate::net::socket;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::tls::TlsConnector;
+
+impl PooledConnection for AsyncConnection {
+    fn id(&self) -> &String {
+        AsyncConnection::id(self)
+    }
+
+    fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+        AsyncConnection::remote_addr(self)
+    }
+
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        AsyncConnection::proxy_header(self)
+    }
+}
+
+/// Slots taken from the pool for connections being opened or validated. The ones not taken
+/// over yet are given back when it is dropped, e.g. when the future holding it is cancelled
+struct Reservation<'a> {
+    pool: &'a AsyncConnectionPool,
+    temporary: bool,
+    slots: usize,
+}
+
+impl<'a> Reservation<'a> {
+    fn new(pool: &'a AsyncConnectionPool, temporary: bool, slots: usize) -> Reservation<'a> {
+        Reservation {
+            pool,
+            temporary,
+            slots,
+        }
+    }
+
+    /// A connection took over one of the slots
+    fn commit(&mut self) {
+        self.slots -= 1;
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        if self.slots == 0 {
+            return;
+        }
+        for _ in 0..self.slots {
+            self.pool.core.cancel(self.temporary);
+        }
+        self.pool.available.notify_waiters();
+    }
+}
+
+/// A caller queued in `acquire_with_priority`. It leaves the queue when dropped
+struct Queued<'a> {
+    pool: &'a AsyncConnectionPool,
+    waiter: Waiter,
+}
+
+impl<'a> Drop for Queued<'a> {
+    fn drop(&mut self) {
+        self.pool.core.dequeue(&mut self.pool.core.lock(), self.waiter);
+        // the next waiter may be able to go
+        self.pool.available.notify_waiters();
+    }
+}
+
+/// A connection being validated in `release`. It is closed if the release is cancelled
+struct Returning<'a> {
+    pool: &'a AsyncConnectionPool,
+    conn: Option<AsyncConnection>,
+}
+
+impl<'a> Drop for Returning<'a> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.checkin(conn, false);
+        }
+    }
+}
+
+/// AsyncConnectionPool which provide pooling capability for AsyncConnection objects
+/// It has support for max number of connections with temporary allowable connections
+pub struct AsyncConnectionPool {
+    core: PoolCore<AsyncConnection>,
+    /// signalled whenever a connection or a slot becomes available
+    available: Notify,
+}
+
+impl AsyncConnectionPool {
+    /// New instance with the settings checked and, if SSL is used, the SSL connector built.
+    /// Fails for invalid settings, unreadable certificate, key or CA files, or if only the
+    /// blocking `Config::validation_probe` is set
+    pub fn new(
+        pool_min_size: usize,
+        pool_max_size: usize,
+        tmp_allowed: bool,
+        conn_config: &config::Config,
+    ) -> Result<AsyncConnectionPool> {
+        if conn_config.validation_probe.is_some() && conn_config.async_validation_probe.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "validation_probe takes a blocking Connection, set async_validation_probe for \
+                 the async pool",
+            ));
+        }
+        let config = conn_config.for_pool()?;
+        Ok(AsyncConnectionPool {
+            core: PoolCore::new(pool_min_size, pool_max_size, tmp_allowed, config),
+            available: Notify::new(),
+        })
+    }
+
+    /// Snapshot of the pool counters
+    pub fn stats(&self) -> PoolStats {
+        self.core.stats()
+    }
+
+    /// Connections currently checked out of the pool.  Empty unless leak detection is enabled
+    pub fn checked_out(&self) -> Vec<leak::CheckoutInfo> {
+        self.core.checked_out()
+    }
+
+    /// Log a warning for connections held longer than `Config::leak_detection_threshold`.
+    /// This is also done on every acquire. Returns the number of newly reported connections
+    pub fn check_leaks(&self) -> usize {
+        self.core.check_leaks()
+    }
+
+    /// Initial the connection pool
+    pub async fn init(&self) -> bool {
+        for i in 0..self.core.min_conns() {
+            if !self.core.reserve_init() {
+                break;
+            }
+            let mut reservation = Reservation::new(self, false, 1);
+            info!("*****Init:Creating connection {}", i);
+            let endpoint = socket::endpoint(self.core.config());
+            match AsyncConnection::connect(self.core.config()).await {
+                Ok(c) => {
+                    let id = c.id().clone();
+                    reservation.commit();
+                    self.core.add_idle(c);
+                    self.available.notify_waiters();
+                    info!("Connection id:{}, Connecting to server {}", id, endpoint);
+                }
+                Err(e) => {
+                    error!("Failed to create a connection to {}. Error: {}", endpoint, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Close idle connections unused for longer than `Config::idle_timeout`, keeping at least
+    /// the min pool size.  Returns the number of closed connections
+    pub fn trim_idle(&self) -> usize {
+        let expired = self.core.take_expired();
+        if !expired.is_empty() {
+            self.available.notify_waiters();
+        }
+        expired.len()
+    }
+
+    /// Resolve `server` again, bypassing the cache, and close the idle connections to addresses
+    /// it no longer resolves to.  `acquire` calls it every `Config::resolve_interval`.
+    /// Does nothing for Unix domain sockets and through a `Config::proxy`. Returns the number
+    /// of closed connections
+    pub async fn refresh_addresses(&self) -> Result<usize> {
+        if !self.core.follows_dns() {
+            return Ok(0);
+        }
+        self.core.start_resolve();
+        let config = self.core.config().clone();
+        // the lookup blocks
+        let addrs = tokio::task::spawn_blocking(move || socket::resolve(&config))
+            .await
+            .map_err(Error::other)??;
+        let stale = self.core.take_stale(&addrs);
+        if !stale.is_empty() {
+            self.available.notify_waiters();
+        }
+        Ok(stale.len())
+    }
+
+    /// Re-resolve if `Config::resolve_interval` has passed since the last time. Only the first
+    /// caller to see it resolves, the others go on with the current addresses
+    async fn refresh_addresses_if_due(&self) {
+        if self.core.claim_resolve() {
+            if let Err(e) = self.refresh_addresses().await {
+                // keep the connections we have, the lookup is retried on the next acquire
+                warn!("Failed to resolve {}: {}", self.core.config().server.clone().unwrap(), e);
+            }
+        }
+    }
+
+    /// The shared SSL connector. Push new certificates with `TlsConnector::set_identity` and
+    /// `TlsConnector::set_trust_anchors`
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn tls_connector(&self) -> Option<&TlsConnector> {
+        self.core.tls_connector()
+    }
+
+    /// Rebuild the SSL connector if the certificate, key or CA files changed. With
+    /// `Config::tls_recycle_on_reload` the connections made with the old connector are closed.
+    /// `acquire` calls it every `Config::tls_reload_interval`. Returns true if it was rebuilt
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub async fn reload_tls(&self) -> Result<bool> {
+        let connector = match self.core.tls_connector() {
+            Some(connector) => connector.clone(),
+            None => return Ok(false),
+        };
+        self.core.start_tls_check();
+        // the files are read on the blocking thread pool
+        let reloaded = tokio::task::spawn_blocking(move || connector.reload_if_changed())
+            .await
+            .map_err(Error::other)?;
+        self.recycle_if_tls_changed();
+        reloaded
+    }
+
+    /// Check the TLS files if `Config::tls_reload_interval` has passed, and recycle the
+    /// connections if the connector was rebuilt since the last time, including by a push
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    async fn reload_tls_if_due(&self) {
+        if self.core.claim_tls_check() {
+            if let Err(e) = self.reload_tls().await {
+                // keep the old connector, the files are checked again on the next acquire
+                warn!("Failed to reload the TLS certificates: {}", e);
+            }
+        } else {
+            self.recycle_if_tls_changed();
+        }
+    }
+
+    /// Close the connections of older connector generations if `Config::tls_recycle_on_reload`
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    fn recycle_if_tls_changed(&self) {
+        if self.core.tls_recycle_due() {
+            info!("TLS connector changed, recycling the connections");
+            self.release_all();
+        }
+    }
+
+    /// Release all :  Remove all connections  from th pool
+    /// Connections currently checked out are closed when they are released
+    pub fn release_all(&self) {
+        let idle = self.core.release_all();
+        self.available.notify_waiters();
+        drop(idle);
+    }
+
+    /// Check the connection with `AsyncConnection::is_valid` and the configured probe
+    async fn validate(&self, conn: &mut AsyncConnection) -> bool {
+        if !conn.is_valid() {
+            return false;
+        }
+        match self.core.config().async_validation_probe {
+            Some(ref probe) => probe(conn).await,
+            None => true,
+        }
+    }
+
+    /// Validate idle connections which have not been checked for `ValidationMode::WhileIdle`
+    /// interval.  Connections failing validation are dropped.  Call it periodically. Returns
+    /// the number of dropped connections
+    pub async fn validate_idle(&self) -> usize {
+        let (stale, generation) = self.core.take_unvalidated();
+        if stale.is_empty() {
+            return 0;
+        }
+        let mut reservation = Reservation::new(self, false, stale.len());
+        let mut dropped = 0;
+        for mut idle in stale {
+            let is_valid = self.validate(&mut idle.conn).await;
+            reservation.commit();
+            if self.core.return_validated(idle, is_valid, generation).is_some() {
+                dropped += 1;
+            }
+        }
+        self.available.notify_waiters();
+        dropped
+    }
+
+    /// Return a checked out connection to the pool
+    fn checkin(&self, conn: AsyncConnection, keep: bool) {
+        let closed = self.core.checkin(conn, keep);
+        self.available.notify_waiters();
+        // close outside of the lock
+        drop(closed);
+    }
+
+    ///Releae connection
+    pub async fn release(&self, conn: AsyncConnection) {
+        let mut returning = Returning {
+            pool: self,
+            conn: Some(conn),
+        };
+        let is_valid = {
+            let conn = returning.conn.as_mut().unwrap();
+            if self.core.validation_mode() == ValidationMode::OnReturn {
+                self.validate(conn).await
+            } else {
+                conn.is_valid()
+            }
+        };
+        if !is_valid {
+            info!("Connection not valid. It should trigger drop connection");
+        }
+        self.checkin(returning.conn.take().unwrap(), is_valid);
+    }
+
+    /// Drop connection.  Use only if disconect.
+    pub fn drop(&self, conn: AsyncConnection) {
+        self.checkin(conn, false);
+    }
+
+    /// Turn a slot into a connection.  Returns None if the idle connection failed validation.
+    /// New connections send `header` if given
+    async fn open_slot(
+        &self,
+        slot: Slot<AsyncConnection>,
+        header: Option<&ProxyHeader>,
+    ) -> Result<Option<AsyncConnection>> {
+        match slot {
+            Slot::Replace(idle) => {
+                info!(
+                    "acquire(): closing idle conn id:{} to make room for another PROXY header",
+                    idle.conn.id()
+                );
+                drop(idle);
+                self.open_new(false, header).await.map(Some)
+            }
+            Slot::Idle(mut idle) => {
+                let mut reservation = Reservation::new(self, false, 1);
+                if self.core.validate_on_borrow(&idle) && !self.validate(&mut idle.conn).await {
+                    info!("acquire(): conn id:{} failed validation. Dropping", idle.conn.id());
+                    return Ok(None);
+                }
+                reservation.commit();
+                self.core.checkout(&idle.conn, false);
+                Ok(Some(idle.conn))
+            }
+            Slot::New(temporary) => self.open_new(temporary, header).await.map(Some),
+        }
+    }
+
+    /// Open a connection in a reserved slot
+    async fn open_new(
+        &self,
+        temporary: bool,
+        header: Option<&ProxyHeader>,
+    ) -> Result<AsyncConnection> {
+        let mut reservation = Reservation::new(self, temporary, 1);
+        let config = self.core.config();
+        let conn = match header {
+            Some(header) => AsyncConnection::connect_with_proxy_header(config, header.clone()).await,
+            None => AsyncConnection::connect(config).await,
+        };
+        match conn {
+            Ok(c) => {
+                info!("New connection id:{}, temporary: {}", c.id().clone(), temporary);
+                reservation.commit();
+                self.core.checkout(&c, temporary);
+                Ok(c)
+            }
+            Err(e) => {
+                error!("Failed to create a connection : {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Aquire Connection.  Opens a temporary connection if the pool is exhausted and they are
+    /// allowed. Idle connections failing validation are dropped and replaced, up to
+    /// `Config::validation_retries`
+    pub async fn acquire(&self) -> Result<AsyncConnection> {
+        self.acquire_matching(None).await
+    }
+
+    /// Aquire a connection which announced `header` with the PROXY protocol when it was opened.
+    /// An idle connection for the same header is reused, otherwise a new one is opened
+    pub async fn acquire_with_proxy_header(&self, header: &ProxyHeader) -> Result<AsyncConnection> {
+        self.acquire_matching(Some(header)).await
+    }
+
+    /// `acquire` for connections with the given PROXY header
+    async fn acquire_matching(&self, header: Option<&ProxyHeader>) -> Result<AsyncConnection> {
+        self.refresh_addresses_if_due().await;
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        self.reload_tls_if_due().await;
+        let mut retries = self.core.validation_retries();
+        let mut use_idle = true;
+        loop {
+            let slot = {
+                let mut state = self.core.lock();
+                self.core.take_slot(&mut state, Priority::Normal, false, use_idle, header)
+            };
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    return Err(Error::other(
+                        "Max pool size has reached and temporary connections are not allowed.",
+                    ))
+                }
+            };
+            if let Some(c) = self.open_slot(slot, header).await? {
+                return Ok(c);
+            }
+            if retries == 0 {
+                use_idle = false;
+            } else {
+                retries -= 1;
+            }
+        }
+    }
+
+    /// Aquire Connection, waiting up to `timeout` for one to be released if the pool is exhausted.
+    /// Same as `acquire_with_priority` with `Priority::Normal`
+    pub async fn acquire_timeout(&self, timeout: Duration) -> Result<AsyncConnection> {
+        self.acquire_with_priority(Priority::Normal, timeout).await
+    }
+
+    /// Aquire Connection, waiting up to `timeout` for one to be released if the pool is exhausted.
+    /// The timeout covers opening a new connection as well. Waiters are served by priority, a
+    /// waiter moves up one priority class every `Config::priority_aging` so low priority callers
+    /// are not starved. Temporary connections are not used
+    pub async fn acquire_with_priority(
+        &self,
+        priority: Priority,
+        timeout: Duration,
+    ) -> Result<AsyncConnection> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        match tokio::time::timeout_at(deadline, self.acquire_queued(priority)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "Timed out waiting for a connection from the pool",
+            )),
+        }
+    }
+
+    /// `acquire_with_priority` without the deadline
+    async fn acquire_queued(&self, priority: Priority) -> Result<AsyncConnection> {
+        self.refresh_addresses_if_due().await;
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        self.reload_tls_if_due().await;
+        let mut retries = self.core.validation_retries();
+        let mut use_idle = true;
+        let waiter = self.core.new_waiter(priority);
+        loop {
+            let slot = self.wait_for_slot(waiter, use_idle).await;
+            if let Some(c) = self.open_slot(slot, None).await? {
+                return Ok(c);
+            }
+            if retries == 0 {
+                use_idle = false;
+            } else {
+                retries -= 1;
+            }
+        }
+    }
+
+    /// Queue up and wait until this waiter is the first one in priority order which can be served
+    async fn wait_for_slot(&self, waiter: Waiter, use_idle: bool) -> Slot<AsyncConnection> {
+        self.core.enqueue(&mut self.core.lock(), waiter);
+        let queued = Queued { pool: self, waiter };
+        loop {
+            let notified = self.available.notified();
+            let mut notified = Box::pin(notified);
+            // register before checking so a release in between is not missed
+            Pin::as_mut(&mut notified).enable();
+            let slot = {
+                let mut state = self.core.lock();
+                self.core.take_slot_if_first(&mut state, waiter, use_idle)
+            };
+            if let Some(slot) = slot {
+                drop(queued);
+                return slot;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UnixListener};
+
+    use crate::net::async_conn::AsyncConnection;
+    use crate::net::config;
+
+    /// Echo server on an OS assigned port
+    async fn start_listener() -> config::Config {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut cfg: config::Config = Default::default();
+        cfg.port = Some(listener.local_addr().unwrap().port());
+        cfg.server = Some("127.0.0.1".to_string());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    loop {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => {
+                                if stream.write_all(&buf[..n]).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        cfg
+    }
+
+    #[tokio::test]
+    async fn test_async_acquire_release() {
+        let cfg = start_listener().await;
+        let pool = super::AsyncConnectionPool::new(1, 2, true, &cfg).unwrap();
+        assert!(pool.init().await);
+        assert_eq!(pool.stats().idle, 1);
+
+        let mut c1 = pool.acquire().await.unwrap();
+        assert!(c1.is_valid());
+        c1.stream.write_all(b"GET google.com\r\n").await.unwrap();
+        c1.stream.flush().await.unwrap();
+        let mut buffer = String::new();
+        c1.stream.read_line(&mut buffer).await.unwrap();
+        assert_eq!(buffer, "GET google.com\r\n");
+
+        let c2 = pool.acquire().await.unwrap();
+        // pool is full, c3 is a temporary connection
+        let c3 = pool.acquire().await.unwrap();
+        assert_eq!(pool.stats().pooled_inuse, 2);
+        assert_eq!(pool.stats().temporary_inuse, 1);
+        pool.release(c1).await;
+        pool.release(c2).await;
+        pool.release(c3).await;
+        let stats = pool.stats();
+        assert_eq!(stats.idle, 2);
+        assert_eq!(stats.pooled_inuse, 0);
+        assert_eq!(stats.temporary_inuse, 0);
+        pool.release_all();
+        assert_eq!(pool.stats().idle, 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_unix_socket() {
+        let path = std::env::temp_dir().join(format!("net-utils-async-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some(format!("unix://{}", path.display()));
+        let pool = super::AsyncConnectionPool::new(0, 1, false, &cfg).unwrap();
+        let mut c = pool.acquire().await.unwrap();
+        assert!(c.is_valid());
+        assert_eq!(c.remote_addr(), None);
+        c.stream.write_all(b"ping\n").await.unwrap();
+        c.stream.flush().await.unwrap();
+        let mut buffer = String::new();
+        c.stream.read_line(&mut buffer).await.unwrap();
+        assert_eq!(buffer, "ping\n");
+        pool.release(c).await;
+        assert_eq!(pool.stats().idle, 1);
+        pool.release_all();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_async_acquire_timeout() {
+        use std::io::ErrorKind;
+        let cfg = start_listener().await;
+        let pool = Arc::new(super::AsyncConnectionPool::new(0, 1, false, &cfg).unwrap());
+        let c1 = pool.acquire().await.unwrap();
+        assert!(pool.acquire().await.is_err());
+        let e = pool.acquire_timeout(Duration::from_millis(100)).await;
+        assert_eq!(e.err().unwrap().kind(), ErrorKind::TimedOut);
+
+        let p1 = pool.clone();
+        let waiter = tokio::spawn(async move {
+            p1.acquire_timeout(Duration::from_millis(5_000)).await.map(|c| c.id().clone())
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let id = c1.id().clone();
+        pool.release(c1).await;
+        assert_eq!(waiter.await.unwrap().unwrap(), id);
+    }
+
+    #[cfg(feature = "async-ssl")]
+    #[test]
+    fn test_async_new_validates() {
+        let mut cfg: config::Config = Default::default();
+        cfg.use_ssl = Some(true);
+        cfg.certificate_file = Some("/nonexistent/client.pem".into());
+        assert!(super::AsyncConnectionPool::new(0, 2, false, &cfg).is_err());
+        cfg.certificate_file = None;
+        let pool = super::AsyncConnectionPool::new(0, 2, false, &cfg).unwrap();
+        assert!(pool.tls_connector().is_some());
+    }
+
+    /// Probe sending a PING which the echo server answers
+    fn ping(c: &mut AsyncConnection) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        Box::pin(async move {
+            if c.stream.write_all(b"PING\r\n").await.is_err() || c.stream.flush().await.is_err() {
+                return false;
+            }
+            let mut line = String::new();
+            match c.stream.read_line(&mut line).await {
+                Ok(_) => line.trim_end() == "PING",
+                Err(_) => false,
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_async_validation_probe() {
+        let mut cfg = start_listener().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        cfg.validation = Some(config::ValidationMode::OnReturn);
+        cfg.async_validation_probe = Some(Arc::new(move |c: &mut AsyncConnection| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            ping(c)
+        }));
+        let pool = super::AsyncConnectionPool::new(1, 2, false, &cfg).unwrap();
+        assert!(pool.init().await);
+        let c = pool.acquire().await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        pool.release(c).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.stats().idle, 1);
+
+        // the blocking probe can not run on async connections
+        cfg.validation_probe = Some(Arc::new(|_: &mut crate::net::conn::Connection| true));
+        assert!(super::AsyncConnectionPool::new(0, 1, false, &cfg).is_ok());
+        cfg.async_validation_probe = None;
+        let e = super::AsyncConnectionPool::new(0, 1, false, &cfg).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_async_validation_on_borrow() {
+        let mut cfg = start_listener().await;
+        cfg.validation = Some(config::ValidationMode::OnBorrow);
+        cfg.validation_retries = Some(1);
+        cfg.async_validation_probe = Some(Arc::new(|_: &mut AsyncConnection| {
+            Box::pin(async { false }) as Pin<Box<dyn Future<Output = bool> + Send>>
+        }));
+        let pool = super::AsyncConnectionPool::new(3, 5, false, &cfg).unwrap();
+        assert!(pool.init().await);
+        // two idle connections fail validation, then a new one is opened
+        let c = pool.acquire().await.unwrap();
+        assert_eq!(pool.stats().idle, 1);
+        assert_eq!(pool.stats().pooled_inuse, 1);
+        pool.drop(c);
+        assert_eq!(pool.stats().pooled_inuse, 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_acquire_cancelled() {
+        use std::io::ErrorKind;
+        use std::time::Instant;
+
+        // a probe which never answers keeps the validation pending
+        let mut cfg = start_listener().await;
+        cfg.validation = Some(config::ValidationMode::OnBorrow);
+        cfg.async_validation_probe = Some(Arc::new(|_: &mut AsyncConnection| {
+            Box::pin(std::future::pending()) as Pin<Box<dyn Future<Output = bool> + Send>>
+        }));
+        let pool = super::AsyncConnectionPool::new(2, 2, false, &cfg).unwrap();
+        assert!(pool.init().await);
+        let pending = tokio::time::timeout(Duration::from_millis(100), pool.acquire()).await;
+        assert!(pending.is_err());
+        assert_eq!(pool.stats().pooled_inuse, 0);
+
+        let started = Instant::now();
+        let e = pool.acquire_timeout(Duration::from_millis(100)).await.err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(1_000));
+        assert_eq!(pool.stats().pooled_inuse, 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_acquire_with_priority_reserved() {
+        use std::io::ErrorKind;
+
+        use crate::net::poolmgr::Priority;
+
+        let mut cfg = start_listener().await;
+        cfg.reserved_conns = Some(1);
+        let pool = super::AsyncConnectionPool::new(0, 2, false, &cfg).unwrap();
+        let c1 = pool.acquire().await.unwrap();
+        assert!(pool.acquire().await.is_err());
+        let timeout = Duration::from_millis(100);
+        let e = pool.acquire_with_priority(Priority::Normal, timeout).await;
+        assert_eq!(e.err().unwrap().kind(), ErrorKind::TimedOut);
+        let c2 = pool.acquire_with_priority(Priority::High, timeout).await.unwrap();
+        let e = pool.acquire_with_priority(Priority::High, timeout).await;
+        assert_eq!(e.err().unwrap().kind(), ErrorKind::TimedOut);
+        pool.release(c1).await;
+        pool.release(c2).await;
+        assert_eq!(pool.stats().pooled_inuse, 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_proxy_header() {
+        use crate::net::proxy_protocol::ProxyHeader;
+
+        let cfg = start_listener().await;
+        let pool = super::AsyncConnectionPool::new(0, 1, false, &cfg).unwrap();
+        let c = pool.acquire().await.unwrap();
+        assert_eq!(c.proxy_header(), None);
+        pool.release(c).await;
+
+        // the idle connection for no header is replaced
+        let header = ProxyHeader::new(
+            "192.0.2.10:40000".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        );
+        let c = pool.acquire_with_proxy_header(&header).await.unwrap();
+        assert_eq!(c.proxy_header(), Some(&header));
+        let id = c.id().clone();
+        pool.release(c).await;
+        let c = pool.acquire_with_proxy_header(&header).await.unwrap();
+        assert_eq!(c.id(), &id);
+        pool.release(c).await;
+        assert_eq!(pool.stats().idle, 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_refresh_addresses() {
+        use std::sync::atomic::Ordering;
+
+        use crate::net::resolver::tests::TestResolver;
+
+        let cfg = start_listener().await;
+        let cfg2 = start_listener().await;
+        let old = format!("127.0.0.1:{}", cfg.port.unwrap()).parse().unwrap();
+        let new = format!("127.0.0.1:{}", cfg2.port.unwrap()).parse().unwrap();
+        let resolver = Arc::new(TestResolver::new(vec![old]));
+        let mut cfg = cfg;
+        cfg.server = Some("service.test".to_string());
+        cfg.resolver = Some(resolver.clone());
+        cfg.resolve_interval = Some(Duration::from_millis(300));
+        let pool = super::AsyncConnectionPool::new(0, 3, false, &cfg).unwrap();
+        let c = pool.acquire().await.unwrap();
+        assert_eq!(c.remote_addr(), Some(old));
+        pool.release(c).await;
+
+        // blue/green switch
+        *resolver.addrs.lock().unwrap() = vec![new];
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let c = pool.acquire().await.unwrap();
+        assert_eq!(c.remote_addr(), Some(new));
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.stats().idle, 0);
+        pool.release(c).await;
+    }
+}
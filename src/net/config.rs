@@ -1,9 +1,66 @@
 //! Configuration for connection object
 //! #![unstable]
+use std::collections::HashMap;
 use std::default::Default;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(feature = "async")]
+use crate::net::async_conn::AsyncConnection;
+use crate::net::conn::Connection;
+use crate::net::proxy::Proxy;
+use crate::net::proxy_protocol::{ProxyHeader, ProxyProtocolVersion};
+use crate::net::resolver::Resolver;
+use crate::net::tls::{self, TlsIdentity, TlsPin, TlsSessionCache, TlsVersion, TrustAnchors};
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::conn;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::tls::TlsConnector;
+
+/// When the pool validates its connections
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationMode {
+    /// Never validate
+    None,
+    /// Validate an idle connection before handing it out in `acquire`
+    OnBorrow,
+    /// Validate a connection when it is given back in `release`
+    OnReturn,
+    /// Validate idle connections which have not been checked for the given interval
+    WhileIdle(Duration),
+}
+
+/// Order in which the pool hands out idle connections
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdleStrategy {
+    /// Oldest idle connection first. Connections rotate and all of them stay warm
+    Fifo,
+    /// Most recently released connection first. Cold connections age out with `Config::idle_timeout`
+    Lifo,
+}
+
+/// User provided connection check, e.g. send a PING and expect a PONG. Returns true if the connection is usable
+pub type ValidationProbe = Arc<dyn Fn(&mut Connection) -> bool + Send + Sync>;
+
+/// `ValidationProbe` for `async_pool::AsyncConnectionPool`. Returns true if the connection is usable
+#[cfg(feature = "async")]
+pub type AsyncValidationProbe = Arc<
+    dyn for<'a> Fn(&'a mut AsyncConnection) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>
+        + Send
+        + Sync,
+>;
+
+/// Protocol exchange run over a new plaintext connection before it is upgraded to TLS, e.g. read
+/// the greeting, send "STARTTLS" and read the server's go-ahead
+pub type StartTlsHook = Arc<dyn Fn(&mut Connection) -> Result<()> + Send + Sync>;
+
 ///Configuration data.
 #[derive(Clone)]
 pub struct Config {
@@ -17,18 +74,129 @@ pub struct Config {
     pub write_timeout: Option<Duration>,
     ///If true, it will assume ssl is enabled
     pub use_ssl: Option<bool>,
-    /// SSL Protocol
-    //pub ssl_protocol : Option<>,
+    /// Connect in plaintext, run the hook and upgrade to TLS with `Connection::upgrade_to_tls`,
+    /// for protocols like SMTP, IMAP, LDAP and PostgreSQL. Takes the place of `use_ssl`
+    pub starttls: Option<StartTlsHook>,
+    /// Lowest TLS version to accept, e.g. `TlsVersion::Tls12`. Defaults to the SSL library's
+    pub tls_min_version: Option<TlsVersion>,
+    /// Highest TLS version to offer. Defaults to the SSL library's
+    pub tls_max_version: Option<TlsVersion>,
+    /// Cipher list for TLS 1.2 and below, in OpenSSL format, e.g. "ECDHE+AESGCM:!aNULL"
+    pub tls_cipher_list: Option<String>,
+    /// TLS 1.3 cipher suites, colon separated, e.g. "TLS_AES_256_GCM_SHA384"
+    pub tls13_ciphersuites: Option<String>,
+    /// Supported groups (curves) for the key exchange, colon separated, e.g. "X25519:P-256"
+    pub tls_groups: Option<String>,
+    /// ALPN protocols to offer, most preferred first, e.g. `b"h2".to_vec()`
+    pub alpn_protocols: Option<Vec<Vec<u8>>>,
+    /// Fail the connection if the server does not select one of `alpn_protocols`. A pool with
+    /// this set only holds connections speaking one of its protocols, use one pool per protocol
+    pub alpn_required: Option<bool>,
+    /// Cache of TLS sessions to resume. A `tls_connector` built from the config uses it and
+    /// makes its own if None. Connections without a `tls_connector` only resume sessions if it
+    /// is set
+    pub tls_session_cache: Option<Arc<TlsSessionCache>>,
+    /// SSL connector to use instead of building one from the settings above on every connect.
+    /// Pools build their own if None, set it to share one between pools
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub tls_connector: Option<TlsConnector>,
+    /// Ask the server to staple an OCSP response, see `TlsInfo::ocsp_response`. It is not checked
+    pub ocsp_stapling: Option<bool>,
     /// Certificate File
     pub certificate_file: Option<PathBuf>,
     /// Private Key File
     pub private_key_file: Option<PathBuf>,
     /// CA File
     pub ca_file: Option<PathBuf>,
-    /// Verify certificate
+    /// Client certificate and key from memory or a PKCS#12 archive. Replaces `certificate_file`
+    /// and `private_key_file`
+    pub tls_identity: Option<TlsIdentity>,
+    /// CA bundles, certificates and directories to trust in addition to `ca_file`. Without it
+    /// the system's CAs are trusted
+    pub trust_anchors: Option<TrustAnchors>,
+    /// Verify the certificate chain
     pub verify: Option<bool>,
+    /// Verify that the certificate matches the server name (DNS or IP SAN). Defaults to `verify`
+    pub verify_hostname: Option<bool>,
+    /// Name sent in SNI and checked against the certificate. Defaults to the host in `server`, set
+    /// it when connecting by IP or through a Unix domain socket
+    pub tls_server_name: Option<String>,
+    /// Accept the server only if a certificate it presents matches one of these pins. Checked in
+    /// addition to `verify`, or on its own if `verify` is off
+    pub tls_pins: Option<Vec<TlsPin>>,
     /// Verify depth
     pub verify_depth: Option<u32>,
+    /// Set TCP_NODELAY, i.e. disable Nagle's algorithm
+    pub tcp_nodelay: Option<bool>,
+    /// Enable SO_KEEPALIVE
+    pub keepalive: Option<bool>,
+    /// Idle time before the first keepalive probe (TCP_KEEPIDLE)
+    pub keepalive_idle: Option<Duration>,
+    /// Time between keepalive probes (TCP_KEEPINTVL)
+    pub keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes before the connection is dropped (TCP_KEEPCNT)
+    pub keepalive_count: Option<u32>,
+    /// SO_SNDBUF
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF
+    pub recv_buffer_size: Option<usize>,
+    /// SO_LINGER
+    pub linger: Option<Duration>,
+    /// IP_TOS (IPV6_TCLASS for IPv6), e.g. DSCP << 2
+    pub ip_tos: Option<u32>,
+    /// Path of a Unix domain socket to connect to instead of `server` and `port`. A server of the
+    /// form `unix:///path/to/socket` does the same
+    pub unix_socket: Option<PathBuf>,
+    /// Proxy to tunnel the connection through. The proxy is connected to instead of the server,
+    /// SSL runs through the tunnel
+    pub proxy: Option<Proxy>,
+    /// Send a HAProxy PROXY protocol header of this version after connecting, before SSL
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Addresses for the PROXY header. Defaults to the local and peer address of the connection
+    pub proxy_protocol_header: Option<ProxyHeader>,
+    /// Local address to bind to before connecting
+    pub local_addr: Option<SocketAddr>,
+    /// Resolver for `server`. Defaults to the system resolver
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Static host name to address overrides, like /etc/hosts. Checked before the resolver
+    pub hosts: Option<HashMap<String, Vec<IpAddr>>>,
+    /// Timeout for establishing the TCP connection, over all the resolved addresses
+    pub connect_timeout: Option<Duration>,
+    /// Delay before racing the next resolved address (Happy Eyeballs, RFC 8305). Defaults to 250ms
+    pub happy_eyeballs_delay: Option<Duration>,
+    /// Pool: warn about connections checked out for longer than this. Leak detection is off if None
+    pub leak_detection_threshold: Option<Duration>,
+    /// Pool: capture a backtrace of the acquire call site for leak reports
+    pub leak_detection_backtrace: Option<bool>,
+    /// Pool: connection validation policy. Defaults to `ValidationMode::None`
+    pub validation: Option<ValidationMode>,
+    /// Pool: probe run in addition to `Connection::is_valid` when validating
+    pub validation_probe: Option<ValidationProbe>,
+    /// Async pool: probe run in addition to `AsyncConnection::is_valid` when validating
+    #[cfg(feature = "async")]
+    pub async_validation_probe: Option<AsyncValidationProbe>,
+    /// Pool: how many idle connections `acquire` may discard before it opens a new one
+    pub validation_retries: Option<usize>,
+    /// Pool: max idle connections kept on release. Defaults to the max pool size
+    pub max_idle: Option<usize>,
+    /// Pool: order in which idle connections are handed out. Defaults to `IdleStrategy::Fifo`
+    pub idle_strategy: Option<IdleStrategy>,
+    /// Pool: idle connections unused for longer than this are closed by `ConnectionPool::trim_idle`
+    pub idle_timeout: Option<Duration>,
+    /// Pool: connections only `Priority::High` callers may use. Taken out of the max pool size
+    pub reserved_conns: Option<usize>,
+    /// Pool: a caller waiting in `acquire_with_priority` moves up one priority class after waiting
+    /// this long. Defaults to 1 second
+    pub priority_aging: Option<Duration>,
+    /// Pool: resolve `server` again this often. Lookups in between are served from a cache and idle
+    /// connections to addresses which are gone are closed. The pool resolves on every connect if None
+    pub resolve_interval: Option<Duration>,
+    /// Pool: check the certificate, key and CA files this often and rebuild the SSL connector if
+    /// they changed. The files are only read when the pool is created if None
+    pub tls_reload_interval: Option<Duration>,
+    /// Pool: close the connections made with the previous certificates after the SSL connector
+    /// is rebuilt. Idle ones are closed right away, checked out ones when they are released
+    pub tls_recycle_on_reload: Option<bool>,
 }
 
 impl Default for Config {
@@ -39,16 +207,118 @@ impl Default for Config {
             read_timeout: Some(Duration::from_millis(60_000)),
             write_timeout: Some(Duration::from_millis(60_000)),
             use_ssl: Some(false),
-           // ssl_protocol: 
+            starttls: None,
+            tls_min_version: None,
+            tls_max_version: None,
+            tls_cipher_list: None,
+            tls13_ciphersuites: None,
+            tls_groups: None,
+            alpn_protocols: None,
+            alpn_required: None,
+            tls_session_cache: None,
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            tls_connector: None,
+            ocsp_stapling: None,
             certificate_file: None,
             private_key_file: None,
             ca_file: None,
+            tls_identity: None,
+            trust_anchors: None,
             verify: None,
+            verify_hostname: None,
+            tls_server_name: None,
+            tls_pins: None,
             verify_depth: None,
+            tcp_nodelay: None,
+            keepalive: None,
+            keepalive_idle: None,
+            keepalive_interval: None,
+            keepalive_count: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+            ip_tos: None,
+            unix_socket: None,
+            proxy: None,
+            proxy_protocol: None,
+            proxy_protocol_header: None,
+            local_addr: None,
+            resolver: None,
+            hosts: None,
+            connect_timeout: None,
+            happy_eyeballs_delay: None,
+            leak_detection_threshold: None,
+            leak_detection_backtrace: None,
+            validation: None,
+            validation_probe: None,
+            #[cfg(feature = "async")]
+            async_validation_probe: None,
+            validation_retries: None,
+            max_idle: None,
+            idle_strategy: None,
+            idle_timeout: None,
+            reserved_conns: None,
+            priority_aging: None,
+            resolve_interval: None,
+            tls_reload_interval: None,
+            tls_recycle_on_reload: None,
         }
     }
 }
 
+impl Config {
+    /// Copy of the config for a pool. If SSL is used the SSL connector is built once here for all
+    /// the pool's connections, unless `tls_connector` is set already. Fails for invalid settings
+    /// or unreadable certificate, key or CA files
+    pub(crate) fn for_pool(&self) -> Result<Config> {
+        self.check_settings()?;
+        #[allow(unused_mut)]
+        let mut config = self.clone();
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        {
+            if config.uses_tls() && config.tls_connector.is_none() {
+                config.tls_connector = Some(TlsConnector::new(&config)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Check the settings which can be checked without connecting: the TLS version range, the
+    /// ALPN protocol names and, if SSL is used, the cipher lists, groups and certificate files
+    pub fn validate(&self) -> Result<()> {
+        self.check_settings()?;
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        {
+            if self.uses_tls() {
+                conn::ssl_connector(self)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Are connections secured, from the start or after `starttls`
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    fn uses_tls(&self) -> bool {
+        self.use_ssl.unwrap_or(false) || self.starttls.is_some()
+    }
+
+    /// The checks of `validate` which do not need the SSL library
+    fn check_settings(&self) -> Result<()> {
+        if let (Some(min), Some(max)) = (self.tls_min_version, self.tls_max_version) {
+            if min > max {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("tls_min_version {} is above tls_max_version {}", min, max),
+                ));
+            }
+        }
+        if let Some(ref protocols) = self.alpn_protocols {
+            tls::alpn_wire_format(protocols)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use std::default::Default;
@@ -63,4 +333,48 @@ pub mod test {
         assert_eq!(c.port, Some(2195));
         assert_eq!(c.read_timeout, Some(Duration::from_millis(60_000)));
     }
+
+    #[test]
+    fn test_validate_tls_versions() {
+        use crate::net::tls::TlsVersion;
+
+        let mut c: super::Config = Default::default();
+        c.tls_min_version = Some(TlsVersion::Tls12);
+        c.tls_max_version = Some(TlsVersion::Tls13);
+        assert!(c.validate().is_ok());
+        c.tls_max_version = Some(TlsVersion::Tls11);
+        assert!(c.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_alpn() {
+        let mut c: super::Config = Default::default();
+        c.alpn_protocols = Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert!(c.validate().is_ok());
+        c.alpn_protocols = Some(vec![]);
+        assert!(c.validate().is_err());
+        c.alpn_protocols = Some(vec![b"h2".to_vec(), vec![]]);
+        assert!(c.validate().is_err());
+        c.alpn_protocols = Some(vec![vec![b'x'; 256]]);
+        assert!(c.validate().is_err());
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn test_validate_ciphers() {
+        let mut c: super::Config = Default::default();
+        c.use_ssl = Some(true);
+        c.tls_cipher_list = Some("ECDHE+AESGCM".to_string());
+        c.tls13_ciphersuites = Some("TLS_AES_256_GCM_SHA384".to_string());
+        c.tls_groups = Some("X25519:P-256".to_string());
+        assert!(c.validate().is_ok());
+        c.tls_cipher_list = Some("NO-SUCH-CIPHER".to_string());
+        assert!(c.validate().is_err());
+        c.tls_cipher_list = None;
+        c.tls13_ciphersuites = Some("TLS_NO_SUCH_SUITE".to_string());
+        assert!(c.validate().is_err());
+        c.tls13_ciphersuites = None;
+        c.tls_groups = Some("no-such-curve".to_string());
+        assert!(c.validate().is_err());
+    }
 }
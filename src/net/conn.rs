@@ -1,29 +1,44 @@
 //! Client Connection.  It supports unsecured and secured(SSL) connection
 //#[cfg(feature = "ssl")]
 //use std::borrow::ToOwned;
-#[cfg(feature = "ssl")]
-use std::error::Error as StdError;
-#[cfg(feature = "ssl")]
 use std::io::{ErrorKind, Error};
 #[cfg(feature = "ssl")]
-use std::sync::{Arc, Mutex};
-#[cfg(feature = "ssl")]
 use std::result::Result as StdResult;
+#[cfg(feature = "ssl")]
+use std::sync::OnceLock;
 use std::io::{Write, Read, Result, BufReader, BufWriter};
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 #[cfg(test)]
 use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
 use std::os::unix::prelude::AsRawFd;
+#[cfg(feature = "ssl")]
+use std::os::unix::io::AsFd;
 
 #[cfg(feature = "ssl")]
-use openssl::ssl::{SslConnectorBuilder, SslMethod, SslStream, SSL_VERIFY_PEER, SSL_VERIFY_NONE};
+use openssl::ex_data::Index;
 #[cfg(feature = "ssl")]
-use openssl::error::ErrorStack;
+use openssl::ssl::{ConnectConfiguration, HandshakeError, Ssl, SslConnector, SslFiletype,
+                   SslMethod, SslRef, SslSessionCacheMode, SslStream, SslVerifyMode,
+                   StatusType};
 #[cfg(feature = "ssl")]
-use openssl::x509;
+use openssl::error::ErrorStack;
 
 // use std::bool;
-use net::config;
+use crate::net::config;
+use crate::net::proxy;
+use crate::net::proxy_protocol::{self, ProxyHeader, ProxyProtocolVersion};
+use crate::net::socket;
+#[cfg(feature = "ssl")]
+use crate::net::ssl_stream::SharedSslStream;
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+use crate::net::rustls_stream::{tls_connect, SharedSslStream};
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+pub(crate) use crate::net::rustls_stream::ssl_connector;
+#[cfg(feature = "ssl")]
+use crate::net::tls;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::tls::TlsInfo;
 use uuid::Uuid;
 
 // pub mod config;
@@ -37,6 +52,8 @@ pub struct Connection {
     pub writer: BufWriter<NetStream>,
     /// Config for connection
     config: config::Config,
+    /// Address the connection was made to. None for Unix domain sockets
+    remote_addr: Option<SocketAddr>,
 }
 
 /// Implementation for Connectio
@@ -46,12 +63,14 @@ impl Connection {
         reader: BufReader<NetStream>,
         writer: BufWriter<NetStream>,
         config: &config::Config,
+        remote_addr: Option<SocketAddr>,
     ) -> Connection {
         Connection {
             id: Uuid::new_v4().to_urn_string(),
             reader: reader,
             writer: writer,
             config: config.clone(),
+            remote_addr,
         }
     }
 
@@ -60,6 +79,17 @@ impl Connection {
     /// Creates a  TCP connection to the specified server.
 
     pub fn connect(config: &config::Config) -> Result<Connection> {
+        if let Some(ref hook) = config.starttls {
+            let mut conn = Connection::connect_internal(config)?;
+            // the exchange is bound by the read and write timeouts
+            if let NetStream::UnsecuredTcpStream(ref tcp) = *conn.reader.get_ref() {
+                tcp.set_read_timeout(config.read_timeout)?;
+                tcp.set_write_timeout(config.write_timeout)?;
+            }
+            hook(&mut conn)?;
+            conn.upgrade_to_tls()?;
+            return Ok(conn);
+        }
         if config.use_ssl.unwrap_or(false) {
             Connection::connect_ssl_internal(config)
         } else {
@@ -67,15 +97,74 @@ impl Connection {
         }
     }
 
+    /// Creates a TCP/SSL connection which announces `header` with the PROXY protocol.
+    /// The version is `Config::proxy_protocol`, v1 if not set
+    pub fn connect_with_proxy_header(
+        config: &config::Config,
+        header: ProxyHeader,
+    ) -> Result<Connection> {
+        let mut config = config.clone();
+        config.proxy_protocol = Some(config.proxy_protocol.unwrap_or(ProxyProtocolVersion::V1));
+        config.proxy_protocol_header = Some(header);
+        Connection::connect(&config)
+    }
+
     /// Creates a  TCP/SSL connection to the specified server.
     ///If already connected, it will drop and reconnect
 
     pub fn reconnect(&mut self) -> Result<Connection> {
-        if self.config.use_ssl.unwrap_or(false) {
-            Connection::connect_ssl_internal(&self.config)
-        } else {
-            Connection::connect_internal(&self.config)
+        Connection::connect(&self.config)
+    }
+
+    /// Upgrade the plaintext connection to TLS in place (STARTTLS): the handshake runs over the
+    /// open socket with the TLS settings of the config. Run the protocol's upgrade exchange
+    /// first. Fails if the server sent anything past it, which would otherwise be read as if it
+    /// came through TLS
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn upgrade_to_tls(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if !self.reader.buffer().is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "data received before the TLS handshake",
+            ));
         }
+        let (reader, writer) = match *self.reader.get_ref() {
+            NetStream::UnsecuredTcpStream(ref tcp) => {
+                let socket = tcp.try_clone()?;
+                socket.set_read_timeout(self.config.read_timeout)?;
+                socket.set_write_timeout(self.config.write_timeout)?;
+                let stream = tls_connect(&self.config, socket)?;
+                let writer = stream.try_clone();
+                (NetStream::SslTcpStream(stream), NetStream::SslTcpStream(writer))
+            }
+            NetStream::UnixStream(ref unix) => {
+                let socket = unix.try_clone()?;
+                socket.set_read_timeout(self.config.read_timeout)?;
+                socket.set_write_timeout(self.config.write_timeout)?;
+                let stream = tls_connect(&self.config, socket)?;
+                let writer = stream.try_clone();
+                (NetStream::SslUnixStream(stream), NetStream::SslUnixStream(writer))
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the connection is already secured",
+                ))
+            }
+        };
+        self.reader = BufReader::new(reader);
+        self.writer = BufWriter::new(writer);
+        Ok(())
+    }
+
+    /// Fails because SSL support was not included at compilation.
+    #[cfg(not(any(feature = "ssl", feature = "rustls")))]
+    pub fn upgrade_to_tls(&mut self) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Cannot upgrade to TLS without compiling with SSL support.",
+        ))
     }
 
     /// Get the connection id
@@ -83,6 +172,50 @@ impl Connection {
         &self.id
     }
 
+    /// The resolved server address which won the connection race, the proxy address if
+    /// `Config::proxy` is set. None for Unix domain sockets
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// The PROXY protocol header given for this connection, if any
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.config.proxy_protocol_header.as_ref()
+    }
+
+    /// Details of the TLS session: version, cipher, the server's certificates, ALPN, resumption
+    /// and the OCSP staple. None for unsecured connections
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match *self.reader.get_ref() {
+            NetStream::SslTcpStream(ref ssl) => Some(ssl.tls_info()),
+            NetStream::SslUnixStream(ref ssl) => Some(ssl.tls_info()),
+            _ => None,
+        }
+    }
+
+    /// The ALPN protocol the server selected. None for unsecured connections or if no protocol
+    /// was negotiated
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn negotiated_protocol(&self) -> Option<Vec<u8>> {
+        match *self.reader.get_ref() {
+            NetStream::SslTcpStream(ref ssl) => ssl.negotiated_protocol(),
+            NetStream::SslUnixStream(ref ssl) => ssl.negotiated_protocol(),
+            _ => None,
+        }
+    }
+
+    /// Was the TLS session resumed from `Config::tls_session_cache` rather than negotiated with
+    /// a full handshake. False for unsecured connections
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn session_reused(&self) -> bool {
+        match *self.reader.get_ref() {
+            NetStream::SslTcpStream(ref ssl) => ssl.session_reused(),
+            NetStream::SslUnixStream(ref ssl) => ssl.session_reused(),
+            _ => false,
+        }
+    }
+
     /// Is Valid connection
     pub fn is_valid(&self) -> bool {
         match self.reader.get_ref() {
@@ -90,9 +223,13 @@ impl Connection {
                 debug!("TCP FD:{}", tcp.as_raw_fd());
                 if tcp.as_raw_fd() < 0 { false } else { true }
             }
-            #[cfg(feature = "ssl")]
+            NetStream::UnixStream(unix) => {
+                debug!("Unix FD:{}", unix.as_raw_fd());
+                unix.as_raw_fd() >= 0
+            }
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
             &NetStream::SslTcpStream(ref ssl) => {
-                let fd = ssl.lock().unwrap().get_ref().as_raw_fd();
+                let fd = ssl.as_raw_fd();
                 debug!("SSL FD:{}", fd);
                 if fd < 0 {
                     return false;
@@ -100,6 +237,11 @@ impl Connection {
                     return true;
                 }
             }
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            NetStream::SslUnixStream(ssl) => {
+                debug!("SSL Unix FD:{}", ssl.as_raw_fd());
+                ssl.as_raw_fd() >= 0
+            }
         }
     }
 
@@ -107,23 +249,48 @@ impl Connection {
     /// Creates a TCP connection with an optional timeout.
 
     fn connect_internal(config: &config::Config) -> Result<Connection> {
+        if let Some(path) = socket::unix_path(config) {
+            info!("Connecting to unix:{}", path.display());
+            let stream_socket = Connection::connect_unix(config, &path)?;
+            send_proxy_header(config, &mut &stream_socket, None)?;
+            let writer_socket = stream_socket.try_clone()?;
+            return Ok(Connection::new(
+                BufReader::new(NetStream::UnixStream(stream_socket)),
+                BufWriter::new(NetStream::UnixStream(writer_socket)),
+                config,
+                None,
+            ));
+        }
         let host: &str = &config.server.clone().unwrap();
         let port = config.port.unwrap();
         info!("Connecting to server {}:{}", host, port);
-        let stream_socket = try!(TcpStream::connect((host, port)));
-        let writer_socket = try!(stream_socket.try_clone());
-        // fixme:  socket.set_timeout(config.connect_timeout);
+        let (stream_socket, remote_addr) = connect_tcp(config)?;
+        send_proxy_header(
+            config,
+            &mut &stream_socket,
+            Some((stream_socket.local_addr()?, remote_addr)),
+        )?;
+        let writer_socket = stream_socket.try_clone()?;
         Ok(Connection::new(
             BufReader::new(NetStream::UnsecuredTcpStream(stream_socket)),
             BufWriter::new(NetStream::UnsecuredTcpStream(writer_socket)),
             config,
+            Some(remote_addr),
         ))
     }
 
+    /// Connects to a Unix domain socket with the read and write timeouts from the config
+    fn connect_unix(config: &config::Config, path: &::std::path::Path) -> Result<UnixStream> {
+        let socket = socket::connect_unix(config, path)?;
+        socket.set_read_timeout(config.read_timeout)?;
+        socket.set_write_timeout(config.write_timeout)?;
+        Ok(socket)
+    }
+
 
 
     /// Panics because SSL support was not included at compilation.
-    #[cfg(not(feature = "ssl"))]
+    #[cfg(not(any(feature = "ssl", feature = "rustls")))]
     fn connect_ssl_internal(config: &config::Config) -> Result<Connection> {
         panic!(
             "Cannot connect to {}:{} over SSL without compiling with SSL support.",
@@ -132,92 +299,282 @@ impl Connection {
         )
     }
 
-    /// Creates a  TCP connection over SSL.
-    #[cfg(feature = "ssl")]
+    /// Creates a  TCP (or Unix domain socket) connection over SSL.
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     fn connect_ssl_internal(config: &config::Config) -> Result<Connection> {
+        if let Some(path) = socket::unix_path(config) {
+            info!("Connecting to unix:{}", path.display());
+            let socket = Connection::connect_unix(config, &path)?;
+            send_proxy_header(config, &mut &socket, None)?;
+            let stream_socket = tls_connect(config, socket)?;
+            let writer_stream = stream_socket.try_clone();
+            return Ok(Connection::new(
+                BufReader::new(NetStream::SslUnixStream(stream_socket)),
+                BufWriter::new(NetStream::SslUnixStream(writer_stream)),
+                config,
+                None,
+            ));
+        }
         let host: &str = &config.server.clone().unwrap();
         let port = config.port.unwrap();
         info!("Connecting to server {}:{}", host, port);
 
-        let socket = try!(TcpStream::connect((host, port)));
+        let (socket, remote_addr) = connect_tcp(config)?;
         socket.set_read_timeout(config.read_timeout);
         socket.set_write_timeout(config.write_timeout);
+        send_proxy_header(config, &mut &socket, Some((socket.local_addr()?, remote_addr)))?;
 
-        let mut ssl_connector_builder = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
-        {
-            let ctx = ssl_connector_builder.builder_mut();
+        // reader and writer share the session but do not block each other
+        let stream_socket = tls_connect(config, socket)?;
+        let writer_stream = stream_socket.try_clone();
+        Ok(Connection::new(
+            BufReader::new(NetStream::SslTcpStream(stream_socket)),
+            BufWriter::new(NetStream::SslTcpStream(writer_stream)),
+            config,
+            Some(remote_addr),
+        ))
+    }
+}
 
-            ctx.set_default_verify_paths().unwrap();
+/// Sends the PROXY protocol header if `Config::proxy_protocol` is set. `addrs` are the local
+/// and remote address of the connection, None for Unix domain sockets
+fn send_proxy_header<W: Write>(
+    config: &config::Config,
+    stream: &mut W,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+) -> Result<()> {
+    match config.proxy_protocol {
+        Some(version) => {
+            proxy_protocol::send(stream, version, config.proxy_protocol_header.as_ref(), addrs)
+        }
+        None => Ok(()),
+    }
+}
 
-            // verify peer
-            if config.verify.unwrap_or(false) {
-                ctx.set_verify(SSL_VERIFY_PEER);
-            } else {
-                ctx.set_verify(SSL_VERIFY_NONE);
-            }
-            // verify depth
-            if config.verify_depth.unwrap_or(0) > 0 {
-                ctx.set_verify_depth(config.verify_depth.unwrap());
-            }
-            if config.certificate_file.is_some() {
-                try!(ssl_to_io(ctx.set_certificate_file(
-                    config.certificate_file.as_ref().unwrap(),
-                    x509::X509_FILETYPE_PEM,
-                )));
-            }
-            if config.private_key_file.is_some() {
-                try!(ssl_to_io(ctx.set_private_key_file(
-                    config.private_key_file.as_ref().unwrap(),
-                    x509::X509_FILETYPE_PEM,
-                )));
-            }
-            if config.ca_file.is_some() {
-                try!(ssl_to_io(ctx.set_ca_file(config.ca_file.as_ref().unwrap())));
-            }
+/// Connects to the server directly or through `Config::proxy`
+fn connect_tcp(config: &config::Config) -> Result<(TcpStream, SocketAddr)> {
+    match config.proxy {
+        Some(ref p) => proxy::connect(config, p),
+        None => socket::connect(config),
+    }
+}
+
+/// Runs the client SSL handshake over a connected socket and shares the stream between the
+/// reader and the writer
+#[cfg(feature = "ssl")]
+fn tls_connect<S: Read + Write + AsFd + AsRawFd + ::std::fmt::Debug>(
+    config: &config::Config,
+    socket: S,
+) -> Result<SharedSslStream<S>> {
+    let stream = ssl_handshake(config, socket)?;
+    SharedSslStream::new(stream, config.read_timeout, config.write_timeout)
+}
+
+/// Runs the client SSL handshake over a connected socket
+#[cfg(feature = "ssl")]
+fn ssl_handshake<S: Read + Write + ::std::fmt::Debug>(
+    config: &config::Config,
+    socket: S,
+) -> Result<SslStream<S>> {
+    let ssl_config = ssl_configure(config)?;
+    match ssl_config.connect(&tls_server_name(config), socket) {
+        Ok(s) => {
+            tls::check_alpn(config, s.ssl().selected_alpn_protocol())?;
+            Ok(s)
         }
-        let ssl_connector = ssl_connector_builder.build();
-
-        let stream_socket_result =
-            match ssl_connector.connect(&*format!("{}:{}", host, port), socket) {
-                Ok(s) => s,
-                Err(e) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        &format!(
-                            "An SSL error occurred. ({}:{})",
-                            e.description(),
-                            e.cause().unwrap()
-                        )
-                            [..],
-                    ));
+        Err(e) => {
+            if let HandshakeError::Failure(ref mid) = e {
+                if let Some(e) = pin_mismatch(config, mid.ssl()) {
+                    return Err(e);
                 }
-            };
+            }
+            Err(Error::new(
+                ErrorKind::Other,
+                &format!("An SSL error occurred. ({})", e)[..],
+            ))
+        }
+    }
+}
+
+/// The error for a failed handshake if the server did not match `Config::tls_pins`
+#[cfg(feature = "ssl")]
+pub(crate) fn pin_mismatch(config: &config::Config, ssl: &SslRef) -> Option<Error> {
+    let pins = config.tls_pins.as_ref()?;
+    let chain = ssl.peer_cert_chain()?;
+    if tls::pins_match(chain, pins) {
+        return None;
+    }
+    chain.iter().next().map(tls::pin_mismatch)
+}
 
 
+/// Name for SNI and hostname verification: `Config::tls_server_name`, else the host in
+/// `Config::server` ("localhost" for Unix domain sockets). SNI is not sent for IP addresses
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+pub(crate) fn tls_server_name(config: &config::Config) -> String {
+    if let Some(ref name) = config.tls_server_name {
+        return name.clone();
+    }
+    if socket::unix_path(config).is_some() {
+        return "localhost".to_string();
+    }
+    // IPv6 literals may come in brackets
+    let server = config.server.clone().unwrap();
+    server.trim_start_matches('[').trim_end_matches(']').to_string()
+}
 
-        let stream_socket = Arc::new(Mutex::new(stream_socket_result));
-        let writer_stream = Arc::clone(&stream_socket);
-        Ok(Connection::new(
-            BufReader::new(NetStream::SslTcpStream(stream_socket)),
-            BufWriter::new(NetStream::SslTcpStream(writer_stream)),
-            config,
-        ))
+/// Is hostname verification on. Follows `Config::verify` unless set explicitly
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+pub(crate) fn verify_hostname(config: &config::Config) -> bool {
+    config.verify_hostname.unwrap_or(config.verify.unwrap_or(false))
+}
 
+/// Key of the TLS session cache: the address connected to and the server name
+#[cfg(feature = "ssl")]
+fn session_endpoint(config: &config::Config) -> String {
+    format!("{}/{}", socket::endpoint(config), tls_server_name(config))
+}
 
+/// Index of the session cache endpoint in the `Ssl` ex data, read by the new session callback
+#[cfg(feature = "ssl")]
+fn endpoint_index() -> Result<Index<Ssl, String>> {
+    static INDEX: OnceLock<Index<Ssl, String>> = OnceLock::new();
+    if let Some(index) = INDEX.get() {
+        return Ok(*index);
+    }
+    let index = ssl_to_io(Ssl::new_ex_index())?;
+    Ok(*INDEX.get_or_init(|| index))
+}
 
+/// Per connection SSL settings: hostname verification, SNI and the session to resume
+#[cfg(feature = "ssl")]
+pub(crate) fn ssl_configure(config: &config::Config) -> Result<ConnectConfiguration> {
+    let mut ssl_config = match config.tls_connector {
+        Some(ref connector) => ssl_to_io(connector.get().configure())?,
+        None => ssl_to_io(ssl_connector(config)?.configure())?,
+    };
+    ssl_config.set_verify_hostname(verify_hostname(config));
+    if config.ocsp_stapling.unwrap_or(false) {
+        ssl_to_io(ssl_config.set_status_type(StatusType::OCSP))?;
+    }
+    let cache = match config.tls_connector {
+        Some(ref connector) => Some(connector.session_cache()),
+        None => config.tls_session_cache.clone(),
+    };
+    if let Some(cache) = cache {
+        let endpoint = session_endpoint(config);
+        if let Some(session) = cache.get(&endpoint) {
+            // safe: the session was negotiated by a connector built from the same config
+            unsafe { ssl_to_io(ssl_config.set_session(&session))? };
+        }
+        ssl_config.set_ex_data(endpoint_index()?, endpoint);
     }
+    Ok(ssl_config)
 }
 
+/// OpenSSL verify errors for a certificate not matching the expected DNS name or IP address
+#[cfg(feature = "ssl")]
+const X509_V_ERR_HOSTNAME_MISMATCH: i32 = 62;
+#[cfg(feature = "ssl")]
+const X509_V_ERR_IP_ADDRESS_MISMATCH: i32 = 64;
+
+/// Builds the SSL connector for the verify, certificate, key and CA settings in the config.
+#[cfg(feature = "ssl")]
+pub(crate) fn ssl_connector(config: &config::Config) -> Result<SslConnector> {
+    let mut ctx = ssl_to_io(SslConnector::builder(SslMethod::tls()))?;
+    if config.trust_anchors.as_ref().is_none_or(|t| t.system_roots) {
+        ssl_to_io(ctx.set_default_verify_paths())?;
+    }
+
+    // verify peer
+    let verify = config.verify.unwrap_or(false);
+    match (verify, verify_hostname(config), config.tls_pins.clone()) {
+        (true, _, None) => ctx.set_verify(SslVerifyMode::PEER),
+        (false, false, None) => ctx.set_verify(SslVerifyMode::NONE),
+        // the hostname is checked while verifying the chain, without `verify` only fail for a
+        // name mismatch. The pins are checked once the chain is built, at the server certificate
+        (_, _, pins) => ctx.set_verify_callback(SslVerifyMode::PEER, move |preverified, x509_ctx| {
+            let error = x509_ctx.error().as_raw();
+            let ok = preverified ||
+                (!verify && error != X509_V_ERR_HOSTNAME_MISMATCH &&
+                     error != X509_V_ERR_IP_ADDRESS_MISMATCH);
+            match pins {
+                Some(ref pins) if ok && x509_ctx.error_depth() == 0 => match x509_ctx.chain() {
+                    Some(chain) => tls::pins_match(chain, pins),
+                    None => x509_ctx.current_cert().is_some_and(|c| tls::pins_match(vec![c], pins)),
+                },
+                _ => ok,
+            }
+        }),
+    }
+    // protocol versions and ciphers
+    if let Some(version) = config.tls_min_version {
+        ssl_to_io(ctx.set_min_proto_version(Some(version.to_openssl())))?;
+    }
+    if let Some(version) = config.tls_max_version {
+        ssl_to_io(ctx.set_max_proto_version(Some(version.to_openssl())))?;
+    }
+    if let Some(ref ciphers) = config.tls_cipher_list {
+        ssl_to_io(ctx.set_cipher_list(ciphers))?;
+    }
+    if let Some(ref suites) = config.tls13_ciphersuites {
+        ssl_to_io(ctx.set_ciphersuites(suites))?;
+    }
+    if let Some(ref groups) = config.tls_groups {
+        ssl_to_io(ctx.set_groups_list(groups))?;
+    }
+    if let Some(ref protocols) = config.alpn_protocols {
+        ssl_to_io(ctx.set_alpn_protos(&tls::alpn_wire_format(protocols)?))?;
+    }
+    // keep new sessions, including TLS 1.3 tickets received after the handshake
+    if let Some(ref cache) = config.tls_session_cache {
+        let cache = cache.clone();
+        let index = endpoint_index()?;
+        ctx.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+        ctx.set_new_session_callback(move |ssl, session| {
+            if let Some(endpoint) = ssl.ex_data(index) {
+                cache.put(endpoint, &session);
+            }
+        });
+    }
+    // verify depth
+    if config.verify_depth.unwrap_or(0) > 0 {
+        ctx.set_verify_depth(config.verify_depth.unwrap());
+    }
+    if let Some(ref identity) = config.tls_identity {
+        tls::set_identity(&mut ctx, identity)?;
+    } else {
+        if config.certificate_file.is_some() {
+            ssl_to_io(ctx.set_certificate_file(
+                config.certificate_file.as_ref().unwrap(),
+                SslFiletype::PEM,
+            ))?;
+        }
+        if config.private_key_file.is_some() {
+            ssl_to_io(ctx.set_private_key_file(
+                config.private_key_file.as_ref().unwrap(),
+                SslFiletype::PEM,
+            ))?;
+        }
+    }
+    if config.ca_file.is_some() {
+        ssl_to_io(ctx.set_ca_file(config.ca_file.as_ref().unwrap()))?;
+    }
+    if let Some(ref anchors) = config.trust_anchors {
+        tls::add_trust_anchors(&mut ctx, anchors)?;
+    }
+    Ok(ctx.build())
+}
 
 /// Converts a Result<T, SslError> isizeo an Result<T>.
 #[cfg(feature = "ssl")]
-fn ssl_to_io<T>(res: StdResult<T, ErrorStack>) -> Result<T> {
+pub(crate) fn ssl_to_io<T>(res: StdResult<T, ErrorStack>) -> Result<T> {
     match res {
         Ok(x) => Ok(x),
         Err(e) => {
             Err(Error::new(
                 ErrorKind::Other,
-                &format!("An SSL error occurred. ({})", e.description())[..],
+                &format!("An SSL error occurred. ({})", e)[..],
             ))
         }
     }
@@ -231,10 +588,16 @@ fn ssl_to_io<T>(res: StdResult<T, ErrorStack>) -> Result<T> {
 pub enum NetStream {
     /// An unsecured TcpStream.
     UnsecuredTcpStream(TcpStream),
-    /// An SSL-secured TcpStream.
-    /// This is only available when compiled with SSL support.
-    #[cfg(feature = "ssl")]
-    SslTcpStream(Arc<Mutex<SslStream<TcpStream>>>),
+    /// An SSL-secured TcpStream. The reader and writer can be used from separate threads.
+    /// This is only available when compiled with SSL (OpenSSL or rustls) support.
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    SslTcpStream(SharedSslStream),
+    /// An unsecured Unix domain socket.
+    UnixStream(UnixStream),
+    /// An SSL-secured Unix domain socket.
+    /// This is only available when compiled with SSL (OpenSSL or rustls) support.
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    SslUnixStream(SharedSslStream<UnixStream>),
 }
 // trait Reader {
 //     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
@@ -243,8 +606,11 @@ impl Read for NetStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         match self {
             &mut NetStream::UnsecuredTcpStream(ref mut stream) => stream.read(buf),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SslTcpStream(ref mut stream) => stream.lock().unwrap().read(buf),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslTcpStream(ref mut stream) => stream.read(buf),
+            &mut NetStream::UnixStream(ref mut stream) => stream.read(buf),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslUnixStream(ref mut stream) => stream.read(buf),
         }
     }
 }
@@ -256,26 +622,32 @@ impl Write for NetStream {
     fn write(&mut self, buf: &[u8]) -> Result<(usize)> {
         match self {
             &mut NetStream::UnsecuredTcpStream(ref mut stream) => stream.write(buf),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SslTcpStream(ref mut stream) => {
-                // Arc::get_mut(stream).unwrap().write(buf)
-                stream.lock().unwrap().write(buf)
-            }
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslTcpStream(ref mut stream) => stream.write(buf),
+            &mut NetStream::UnixStream(ref mut stream) => stream.write(buf),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslUnixStream(ref mut stream) => stream.write(buf),
         }
 
     }
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
         match self {
             &mut NetStream::UnsecuredTcpStream(ref mut stream) => stream.write_all(buf),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SslTcpStream(ref mut stream) => stream.lock().unwrap().write_all(buf),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslTcpStream(ref mut stream) => stream.write_all(buf),
+            &mut NetStream::UnixStream(ref mut stream) => stream.write_all(buf),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslUnixStream(ref mut stream) => stream.write_all(buf),
         }
     }
     fn flush(&mut self) -> Result<()> {
         match self {
             &mut NetStream::UnsecuredTcpStream(ref mut stream) => stream.flush(),
-            #[cfg(feature = "ssl")]
-            &mut NetStream::SslTcpStream(ref mut stream) => stream.lock().unwrap().flush(),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslTcpStream(ref mut stream) => stream.flush(),
+            &mut NetStream::UnixStream(ref mut stream) => stream.flush(),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslUnixStream(ref mut stream) => stream.flush(),
         }
     }
 }
@@ -295,10 +667,638 @@ impl Drop for Connection {
                 stream.shutdown(Shutdown::Read);
                 stream.shutdown(Shutdown::Write);
             }
-            #[cfg(feature = "ssl")]
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
             &mut NetStream::SslTcpStream(ref mut ssl) => {
-                ssl.lock().unwrap().shutdown();
+                ssl.shutdown();
+            }
+            &mut NetStream::UnixStream(ref mut stream) => {
+                stream.shutdown(Shutdown::Both);
+            }
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            &mut NetStream::SslUnixStream(ref mut ssl) => {
+                ssl.shutdown();
             }
         }
     }
 }
+
+/// Runs against the backend compiled in, OpenSSL or rustls. The test servers use OpenSSL
+#[cfg(all(test, any(feature = "ssl", feature = "rustls")))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use openssl::ssl::{select_next_proto, AlpnError, NameType, SslAcceptor, SslMethod,
+                       SslVerifyMode};
+
+    use super::Connection;
+    use crate::net::config;
+    use crate::net::test_support::{test_ca, test_cert};
+
+    /// SSL echo server with a certificate for localhost and 127.0.0.1 issued by a test CA. It
+    /// selects "h2" if the client offers it with ALPN and staples "test staple" as the OCSP
+    /// response if asked. Returns the config to reach it with the CA trusted, and the SNI names it receives
+    fn start_server(name: &str) -> (config::Config, Receiver<Option<String>>) {
+        let ca = test_ca();
+        let (cert, key) = test_cert(&ca, &["localhost", "127.0.0.1"]);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let (tx, rx) = channel();
+        acceptor.set_servername_callback(move |ssl, _| {
+            tx.send(ssl.servername(NameType::HOST_NAME).map(|s| s.to_string())).unwrap();
+            Ok(())
+        });
+        acceptor.set_status_callback(|ssl| {
+            ssl.set_ocsp_status(b"test staple")?;
+            Ok(true)
+        }).unwrap();
+        acceptor.set_alpn_select_callback(|_, client| {
+            select_next_proto(b"\x02h2", client).ok_or(AlpnError::NOACK)
+        });
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || for stream in listener.incoming() {
+            if let Ok(mut stream) = acceptor.accept(stream.unwrap()) {
+                let mut buf = [0; 4];
+                if stream.read_exact(&mut buf).is_ok() {
+                    let _ = stream.write_all(&buf);
+                }
+            }
+        });
+
+        let ca_file: PathBuf =
+            env::temp_dir().join(format!("net-utils-ca-{}-{}.pem", process::id(), name));
+        fs::write(&ca_file, ca.0.to_pem().unwrap()).unwrap();
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some("127.0.0.1".to_string());
+        cfg.port = Some(port);
+        cfg.use_ssl = Some(true);
+        cfg.verify = Some(true);
+        cfg.ca_file = Some(ca_file);
+        (cfg, rx)
+    }
+
+    fn ping(cfg: &config::Config) -> ::std::io::Result<()> {
+        let mut conn = Connection::connect(cfg)?;
+        conn.writer.write_all(b"ping")?;
+        conn.writer.flush()?;
+        let mut buf = [0; 4];
+        conn.reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_versions() {
+        use crate::net::tls::TlsVersion;
+
+        let (mut cfg, _rx) = start_server("versions");
+        cfg.tls_max_version = Some(TlsVersion::Tls12);
+        let conn = Connection::connect(&cfg).unwrap();
+        assert_eq!(conn.tls_info().unwrap().version, Some(TlsVersion::Tls12));
+        // the server handles one connection at a time
+        drop(conn);
+
+        cfg.tls_min_version = Some(TlsVersion::Tls13);
+        cfg.tls_max_version = None;
+        let info = Connection::connect(&cfg).unwrap().tls_info().unwrap();
+        assert_eq!(info.version, Some(TlsVersion::Tls13));
+        assert!(info.cipher.unwrap().starts_with("TLS_"));
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn test_tls_ciphers() {
+        use crate::net::tls::TlsVersion;
+
+        let (mut cfg, _rx) = start_server("ciphers");
+        cfg.tls_max_version = Some(TlsVersion::Tls12);
+        cfg.tls_cipher_list = Some("ECDHE-RSA-AES256-GCM-SHA384".to_string());
+        let conn = Connection::connect(&cfg).unwrap();
+        let info = conn.tls_info().unwrap();
+        assert_eq!(info.version, Some(TlsVersion::Tls12));
+        assert_eq!(info.cipher, Some("ECDHE-RSA-AES256-GCM-SHA384".to_string()));
+        drop(conn);
+
+        cfg.tls_min_version = Some(TlsVersion::Tls13);
+        cfg.tls_max_version = None;
+        cfg.tls_cipher_list = None;
+        cfg.tls13_ciphersuites = Some("TLS_CHACHA20_POLY1305_SHA256".to_string());
+        let info = Connection::connect(&cfg).unwrap().tls_info().unwrap();
+        assert_eq!(info.version, Some(TlsVersion::Tls13));
+        assert_eq!(info.cipher, Some("TLS_CHACHA20_POLY1305_SHA256".to_string()));
+    }
+
+    #[test]
+    fn test_tls_info() {
+        use std::net::IpAddr;
+        use std::time::Duration;
+
+        let (mut cfg, _rx) = start_server("info");
+        let conn = Connection::connect(&cfg).unwrap();
+        let info = conn.tls_info().unwrap();
+        assert_eq!(info.peer_certificates.len(), 1);
+        let cert = &info.peer_certificates[0];
+        assert_eq!(cert.subject, "CN=localhost");
+        assert_eq!(cert.issuer, "CN=net-utils test CA");
+        assert_eq!(cert.dns_names, vec!["localhost".to_string()]);
+        assert_eq!(cert.ip_addresses, vec!["127.0.0.1".parse::<IpAddr>().unwrap()]);
+        // the test certificates are valid for a day
+        let left = cert.expires_in().unwrap();
+        assert!(left > Duration::from_secs(23 * 3600) && left <= Duration::from_secs(86_400));
+        assert!(cert.not_before <= ::std::time::SystemTime::now());
+        assert!(openssl::x509::X509::from_der(&cert.der).is_ok());
+        assert_eq!(info.alpn_protocol, None);
+        assert!(!info.session_reused);
+        assert_eq!(info.ocsp_response, None);
+        drop(conn);
+
+        cfg.alpn_protocols = Some(vec![b"h2".to_vec()]);
+        let info = Connection::connect(&cfg).unwrap().tls_info().unwrap();
+        assert_eq!(info.alpn_protocol, Some(b"h2".to_vec()));
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn test_ocsp_stapling() {
+        let (mut cfg, _rx) = start_server("ocsp");
+        cfg.ocsp_stapling = Some(true);
+        let info = Connection::connect(&cfg).unwrap().tls_info().unwrap();
+        assert_eq!(info.ocsp_response, Some(b"test staple".to_vec()));
+    }
+
+    #[test]
+    fn test_alpn() {
+        let (mut cfg, _rx) = start_server("alpn");
+        let conn = Connection::connect(&cfg).unwrap();
+        assert_eq!(conn.negotiated_protocol(), None);
+        drop(conn);
+
+        cfg.alpn_protocols = Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        cfg.alpn_required = Some(true);
+        let conn = Connection::connect(&cfg).unwrap();
+        assert_eq!(conn.negotiated_protocol(), Some(b"h2".to_vec()));
+        drop(conn);
+
+        // the server does not speak the protocol
+        cfg.alpn_protocols = Some(vec![b"custom/1".to_vec()]);
+        cfg.alpn_required = Some(false);
+        let conn = Connection::connect(&cfg).unwrap();
+        assert_eq!(conn.negotiated_protocol(), None);
+        drop(conn);
+        cfg.alpn_required = Some(true);
+        assert!(Connection::connect(&cfg).is_err());
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn test_session_resumption() {
+        use std::sync::Arc;
+
+        use crate::net::tls::{TlsSessionCache, TlsVersion};
+
+        for &version in &[TlsVersion::Tls12, TlsVersion::Tls13] {
+            let (mut cfg, _rx) = start_server(&format!("resume-{}", version));
+            cfg.tls_min_version = Some(version);
+            cfg.tls_max_version = Some(version);
+            let conn = Connection::connect(&cfg).unwrap();
+            assert!(!conn.session_reused());
+            drop(conn);
+
+            let cache = Arc::new(TlsSessionCache::new());
+            cfg.tls_session_cache = Some(cache.clone());
+            for &reused in &[false, true, true] {
+                let mut conn = Connection::connect(&cfg).unwrap();
+                // TLS 1.3 tickets arrive with the first read
+                conn.writer.write_all(b"ping").unwrap();
+                conn.writer.flush().unwrap();
+                let mut buf = [0; 4];
+                conn.reader.read_exact(&mut buf).unwrap();
+                assert_eq!(conn.session_reused(), reused, "{}", version);
+                drop(conn);
+            }
+            assert_eq!(cache.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_trust_anchors() {
+        use crate::net::tls::{TrustAnchor, TrustAnchors};
+        use openssl::x509::X509;
+
+        let (mut cfg, _rx) = start_server("anchors");
+        let ca_file = cfg.ca_file.take().unwrap();
+        let ca_pem = fs::read(&ca_file).unwrap();
+        // neither the system's CAs nor another CA know the test CA
+        let other = test_ca().0.to_der().unwrap();
+        cfg.trust_anchors = Some(TrustAnchors::new(vec![TrustAnchor::Der(other)]));
+        assert!(ping(&cfg).is_err());
+        cfg.trust_anchors = Some(Default::default());
+        assert!(ping(&cfg).is_err());
+
+        let ca = X509::from_pem(&ca_pem).unwrap();
+        let dir = env::temp_dir().join(format!("net-utils-ca-{}-dir", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(format!("{:08x}.0", ca.subject_name_hash())), &ca_pem).unwrap();
+        for anchor in vec![
+            TrustAnchor::Pem(ca_pem.clone()),
+            TrustAnchor::Der(ca.to_der().unwrap()),
+            TrustAnchor::File(ca_file),
+            TrustAnchor::Dir(dir.clone()),
+        ] {
+            cfg.trust_anchors = Some(TrustAnchors::new(vec![anchor.clone()]));
+            assert!(ping(&cfg).is_ok(), "{:?}", anchor);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "ssl")]
+    #[test]
+    fn test_tls_pins() {
+        use std::net::TcpStream;
+
+        use crate::net::tls::{self, TlsPin};
+        use openssl::ssl::SslConnector;
+        use openssl::x509::X509;
+
+        let (mut cfg, _rx) = start_server("pins");
+        let ca = X509::from_pem(&fs::read(cfg.ca_file.as_ref().unwrap()).unwrap()).unwrap();
+        let (_, ca_spki_pin) = tls::pins_of(&ca).unwrap();
+        // the server sends its certificate only, the CA is matched in the verified chain
+        let other = TlsPin::Spki([0; 32]);
+        cfg.tls_pins = Some(vec![other, ca_spki_pin]);
+        ping(&cfg).unwrap();
+
+        // pinning the server certificate instead of CA validation
+        cfg.verify = Some(false);
+        cfg.verify_hostname = Some(false);
+        cfg.ca_file = None;
+        let stream = TcpStream::connect(("127.0.0.1", cfg.port.unwrap())).unwrap();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let ssl = connector.build().connect("localhost", stream).unwrap();
+        let (server_pin, _) = tls::pins_of(&ssl.ssl().peer_certificate().unwrap()).unwrap();
+        drop(ssl);
+        cfg.tls_pins = Some(vec![server_pin]);
+        ping(&cfg).unwrap();
+        cfg.tls_pins = Some(vec![other]);
+        let err = ping(&cfg).unwrap_err().to_string();
+        assert!(err.contains("TLS pin mismatch"), "{}", err);
+        assert!(err.contains(&server_pin.to_string()), "{}", err);
+        assert!(err.contains("spki-sha256:"), "{}", err);
+    }
+
+    #[test]
+    fn test_ip_san() {
+        let (cfg, rx) = start_server("ip");
+        ping(&cfg).unwrap();
+        // no SNI for IP addresses
+        assert_eq!(rx.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn test_sni_and_server_name() {
+        let (mut cfg, rx) = start_server("sni");
+        cfg.tls_server_name = Some("localhost".to_string());
+        ping(&cfg).unwrap();
+        assert_eq!(rx.recv().unwrap(), Some("localhost".to_string()));
+
+        cfg.server = Some("localhost".to_string());
+        cfg.tls_server_name = None;
+        ping(&cfg).unwrap();
+        // the port is not part of the name
+        assert_eq!(rx.recv().unwrap(), Some("localhost".to_string()));
+    }
+
+    #[test]
+    fn test_hostname_mismatch() {
+        let (mut cfg, _rx) = start_server("mismatch");
+        cfg.tls_server_name = Some("wrong.example.com".to_string());
+        assert!(ping(&cfg).is_err());
+
+        // chain verification alone accepts it
+        cfg.verify_hostname = Some(false);
+        ping(&cfg).unwrap();
+    }
+
+    #[test]
+    fn test_hostname_without_chain() {
+        let (mut cfg, _rx) = start_server("nochain");
+        // the CA is unknown but only the name is checked
+        cfg.ca_file = None;
+        cfg.verify = Some(false);
+        cfg.verify_hostname = Some(true);
+        cfg.tls_server_name = Some("localhost".to_string());
+        ping(&cfg).unwrap();
+        cfg.tls_server_name = Some("wrong.example.com".to_string());
+        assert!(ping(&cfg).is_err());
+
+        // both checks off
+        cfg.verify_hostname = Some(false);
+        ping(&cfg).unwrap();
+
+        // the unknown CA fails chain verification
+        cfg.verify = Some(true);
+        cfg.tls_server_name = Some("localhost".to_string());
+        assert!(ping(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_connector_resumption() {
+        use crate::net::tls::{TlsConnector, TlsVersion};
+
+        for &version in &[TlsVersion::Tls12, TlsVersion::Tls13] {
+            let (mut cfg, _rx) = start_server(&format!("connector-{}", version));
+            cfg.tls_min_version = Some(version);
+            cfg.tls_max_version = Some(version);
+            cfg.tls_connector = Some(TlsConnector::new(&cfg).unwrap());
+            for &reused in &[false, true] {
+                // TLS 1.3 tickets arrive with the first read
+                let mut conn = Connection::connect(&cfg).unwrap();
+                conn.writer.write_all(b"ping").unwrap();
+                conn.writer.flush().unwrap();
+                let mut buf = [0; 4];
+                conn.reader.read_exact(&mut buf).unwrap();
+                assert_eq!(conn.session_reused(), reused, "{}", version);
+                drop(conn);
+            }
+        }
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        use crate::net::tls::TlsIdentity;
+
+        // echo server which requires a certificate issued by its own CA
+        let ca = test_ca();
+        let (cert, key) = test_cert(&ca, &["localhost"]);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.cert_store_mut().add_cert(ca.0.clone()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || for stream in listener.incoming() {
+            if let Ok(mut stream) = acceptor.accept(stream.unwrap()) {
+                let mut buf = [0; 4];
+                if stream.read_exact(&mut buf).is_ok() {
+                    let _ = stream.write_all(&buf);
+                }
+            }
+        });
+
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some("127.0.0.1".to_string());
+        cfg.port = Some(port);
+        cfg.use_ssl = Some(true);
+        cfg.read_timeout = Some(::std::time::Duration::from_millis(5_000));
+        assert!(ping(&cfg).is_err());
+
+        let (client, client_key) = test_cert(&ca, &["client"]);
+        cfg.tls_identity = Some(TlsIdentity::Pem {
+            certs: client.to_pem().unwrap(),
+            key: client_key.private_key_to_pem_pkcs8().unwrap(),
+            passphrase: None,
+        });
+        ping(&cfg).unwrap();
+        cfg.tls_identity = Some(TlsIdentity::Der {
+            certs: vec![client.to_der().unwrap()],
+            key: client_key.private_key_to_der().unwrap(),
+        });
+        ping(&cfg).unwrap();
+
+        cfg.tls_identity = None;
+        let dir = env::temp_dir();
+        let cert_file = dir.join(format!("net-utils-{}-mtls.pem", process::id()));
+        let key_file = dir.join(format!("net-utils-{}-mtls.key", process::id()));
+        fs::write(&cert_file, client.to_pem().unwrap()).unwrap();
+        fs::write(&key_file, client_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        cfg.certificate_file = Some(cert_file.clone());
+        cfg.private_key_file = Some(key_file.clone());
+        ping(&cfg).unwrap();
+
+        // issued by another CA
+        let (other, other_key) = test_cert(&test_ca(), &["client"]);
+        fs::write(&cert_file, other.to_pem().unwrap()).unwrap();
+        fs::write(&key_file, other_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        assert!(ping(&cfg).is_err());
+        fs::remove_file(&cert_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+    }
+
+    #[cfg(all(feature = "rustls", not(feature = "ssl")))]
+    #[test]
+    fn test_rustls_unsupported_settings() {
+        use std::io::ErrorKind;
+
+        use crate::net::tls::{TlsIdentity, TlsPin, TlsVersion};
+
+        let (cfg, _rx) = start_server("unsupported");
+        ping(&cfg).unwrap();
+        let mut settings: Vec<Box<dyn Fn(&mut config::Config)>> = vec![
+            Box::new(|c| c.tls_cipher_list = Some("ECDHE+AESGCM".to_string())),
+            Box::new(|c| c.tls_groups = Some("X25519".to_string())),
+            Box::new(|c| c.tls_pins = Some(vec![TlsPin::Spki([0; 32])])),
+            Box::new(|c| c.ocsp_stapling = Some(true)),
+            Box::new(|c| c.tls_session_cache = Some(Default::default())),
+            Box::new(|c| c.tls_max_version = Some(TlsVersion::Tls11)),
+            Box::new(|c| {
+                c.tls_identity = Some(TlsIdentity::Pkcs12 {
+                    der: vec![],
+                    password: String::new(),
+                })
+            }),
+        ];
+        for set in settings.drain(..) {
+            let mut c = cfg.clone();
+            set(&mut c);
+            assert_eq!(c.validate().unwrap_err().kind(), ErrorKind::Unsupported);
+            assert!(ping(&c).is_err());
+        }
+    }
+
+    /// Plaintext server which greets, waits for "STARTTLS", answers with "220 go ahead" followed
+    /// by `extra` and then runs the TLS echo of `start_server`
+    fn start_starttls_server(name: &str, extra: &'static [u8]) -> config::Config {
+        use std::io::{BufRead, BufReader};
+
+        let ca = test_ca();
+        let (cert, key) = test_cert(&ca, &["localhost", "127.0.0.1"]);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            let _ = stream.write_all(b"220 ready\r\n");
+            if reader.read_line(&mut line).is_err() || line != "STARTTLS\r\n" {
+                continue;
+            }
+            let reply = [&b"220 go ahead\r\n"[..], extra].concat();
+            let _ = stream.write_all(&reply);
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                let mut buf = [0; 4];
+                if stream.read_exact(&mut buf).is_ok() {
+                    let _ = stream.write_all(&buf);
+                }
+            }
+        });
+
+        let ca_file: PathBuf =
+            env::temp_dir().join(format!("net-utils-ca-{}-{}.pem", process::id(), name));
+        fs::write(&ca_file, ca.0.to_pem().unwrap()).unwrap();
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some("127.0.0.1".to_string());
+        cfg.port = Some(port);
+        cfg.verify = Some(true);
+        cfg.ca_file = Some(ca_file);
+        cfg
+    }
+
+    fn starttls(conn: &mut Connection) -> ::std::io::Result<()> {
+        use std::io::{BufRead, Error, ErrorKind};
+
+        let mut line = String::new();
+        conn.reader.read_line(&mut line)?;
+        conn.writer.write_all(b"STARTTLS\r\n")?;
+        conn.writer.flush()?;
+        line.clear();
+        conn.reader.read_line(&mut line)?;
+        if !line.starts_with("220") {
+            return Err(Error::new(ErrorKind::InvalidData, line));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_to_tls() {
+        use std::io::ErrorKind;
+
+        let cfg = start_starttls_server("upgrade", b"");
+        let mut conn = Connection::connect(&cfg).unwrap();
+        assert!(conn.tls_info().is_none());
+        starttls(&mut conn).unwrap();
+        conn.upgrade_to_tls().unwrap();
+        assert!(conn.tls_info().is_some());
+        conn.writer.write_all(b"ping").unwrap();
+        conn.writer.flush().unwrap();
+        let mut buf = [0; 4];
+        conn.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(conn.upgrade_to_tls().unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_upgrade_rejects_buffered_data() {
+        use std::io::ErrorKind;
+
+        let cfg = start_starttls_server("injected", b"250 injected\r\n");
+        let mut conn = Connection::connect(&cfg).unwrap();
+        starttls(&mut conn).unwrap();
+        assert_eq!(conn.upgrade_to_tls().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_starttls_pool() {
+        use std::sync::Arc;
+
+        use crate::net::poolmgr::ConnectionPool;
+
+        let mut cfg = start_starttls_server("pool", b"");
+        cfg.starttls = Some(Arc::new(starttls));
+        let pool = ConnectionPool::new(1, 1, true, &cfg).unwrap();
+        let mut conn = pool.acquire().unwrap();
+        assert!(conn.tls_info().is_some());
+        conn.writer.write_all(b"ping").unwrap();
+        conn.writer.flush().unwrap();
+        let mut buf = [0; 4];
+        conn.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        pool.release(conn);
+    }
+
+    #[test]
+    fn test_mtls() {
+        use crate::net::test_support::{echo_server, TestPki};
+
+        let pki = TestPki::new();
+        let port = echo_server(&pki.server, Some(&pki.ca.0));
+        let cfg = pki.config(port, "mtls");
+        let conn = Connection::connect(&cfg).unwrap();
+        let info = conn.tls_info().unwrap();
+        assert_eq!(info.peer_certificates[0].subject, "CN=localhost");
+        drop(conn);
+        ping(&cfg).unwrap();
+    }
+
+    #[test]
+    fn test_mtls_wrong_ca() {
+        use crate::net::test_support::{ca_file, echo_server, identity, TestPki};
+
+        let pki = TestPki::new();
+        let other = TestPki::new();
+        let port = echo_server(&pki.server, Some(&pki.ca.0));
+
+        // the server certificate is not issued by the trusted CA
+        let mut cfg = pki.config(port, "mtls-wrong-ca");
+        cfg.ca_file = Some(ca_file(&other.ca.0, "mtls-other-ca"));
+        assert!(Connection::connect(&cfg).is_err());
+
+        // the client certificate is not issued by the CA of the server
+        let mut cfg = pki.config(port, "mtls-wrong-ca");
+        cfg.tls_identity = Some(identity(&other.client));
+        assert!(ping(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_mtls_expired() {
+        use crate::net::test_support::{echo_server, expired_cert, identity, TestPki};
+
+        let pki = TestPki::new();
+        let expired = expired_cert(&pki.ca, &["localhost", "127.0.0.1"]);
+        let port = echo_server(&expired, Some(&pki.ca.0));
+        assert!(Connection::connect(&pki.config(port, "mtls-expired")).is_err());
+
+        let port = echo_server(&pki.server, Some(&pki.ca.0));
+        let mut cfg = pki.config(port, "mtls-expired");
+        cfg.tls_identity = Some(identity(&expired_cert(&pki.ca, &["client"])));
+        assert!(ping(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_mtls_hostname_mismatch() {
+        use crate::net::test_support::{echo_server, test_cert, TestPki};
+
+        let pki = TestPki::new();
+        let port = echo_server(&test_cert(&pki.ca, &["other.example"]), Some(&pki.ca.0));
+        let mut cfg = pki.config(port, "mtls-mismatch");
+        assert!(Connection::connect(&cfg).is_err());
+        cfg.tls_server_name = Some("other.example".to_string());
+        ping(&cfg).unwrap();
+    }
+
+    #[test]
+    fn test_mtls_missing_client_cert() {
+        use crate::net::test_support::{echo_server, TestPki};
+
+        let pki = TestPki::new();
+        let port = echo_server(&pki.server, Some(&pki.ca.0));
+        let mut cfg = pki.config(port, "mtls-missing");
+        cfg.tls_identity = None;
+        assert!(ping(&cfg).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Connection leak detection.  Tracks who acquired each checked-out connection
+//! so a pool which is slowly running out of connections can be diagnosed.
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Details about a connection which is currently checked out of the pool
+#[derive(Clone, Debug)]
+pub struct CheckoutInfo {
+    /// Connection id
+    pub id: String,
+    /// Name (or id if unnamed) of the thread which acquired the connection
+    pub thread: String,
+    /// Wall clock time at which the connection was acquired
+    pub acquired_at: SystemTime,
+    /// Backtrace of the acquire call site, if capturing is enabled
+    pub backtrace: Option<String>,
+    started: Instant,
+    warned: bool,
+}
+
+impl CheckoutInfo {
+    /// How long the connection has been held
+    pub fn held_for(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Records the acquiring thread, timestamp and optional backtrace for each
+/// checked-out connection and warns about connections held too long.
+pub struct LeakDetector {
+    threshold: Duration,
+    capture_backtrace: bool,
+    checked_out: Mutex<HashMap<String, CheckoutInfo>>,
+}
+
+impl LeakDetector {
+    /// New instance.  A warning is logged once for every connection held longer than `threshold`
+    pub fn new(threshold: Duration, capture_backtrace: bool) -> LeakDetector {
+        LeakDetector {
+            threshold,
+            capture_backtrace,
+            checked_out: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that connection `id` was handed out to the current thread
+    pub fn on_acquire(&self, id: &str) {
+        let current = thread::current();
+        let thread = match current.name() {
+            Some(name) => name.to_string(),
+            None => format!("{:?}", current.id()),
+        };
+        let backtrace = if self.capture_backtrace {
+            Some(Backtrace::force_capture().to_string())
+        } else {
+            None
+        };
+        let info = CheckoutInfo {
+            id: id.to_string(),
+            thread,
+            acquired_at: SystemTime::now(),
+            backtrace,
+            started: Instant::now(),
+            warned: false,
+        };
+        self.checked_out.lock().unwrap().insert(id.to_string(), info);
+    }
+
+    /// Record that connection `id` came back to the pool (or was dropped)
+    pub fn on_release(&self, id: &str) {
+        if let Some(info) = self.checked_out.lock().unwrap().remove(id) {
+            if info.warned {
+                info!(
+                    "Connection id:{} held for {:?} by thread {} has been returned",
+                    id,
+                    info.held_for(),
+                    info.thread
+                );
+            }
+        }
+    }
+
+    /// Log a warning for every connection held longer than the threshold.
+    /// Each connection is reported only once.  Returns the number of newly reported connections.
+    pub fn check(&self) -> usize {
+        let mut reported = 0;
+        let mut checked_out = self.checked_out.lock().unwrap();
+        for info in checked_out.values_mut() {
+            if info.warned || info.held_for() < self.threshold {
+                continue;
+            }
+            info.warned = true;
+            reported += 1;
+            match info.backtrace {
+                Some(ref bt) => warn!(
+                    "Possible connection leak: conn id:{} held for {:?} by thread {}. Acquired at:\n{}",
+                    info.id,
+                    info.held_for(),
+                    info.thread,
+                    bt
+                ),
+                None => warn!(
+                    "Possible connection leak: conn id:{} held for {:?} by thread {}",
+                    info.id,
+                    info.held_for(),
+                    info.thread
+                ),
+            }
+        }
+        reported
+    }
+
+    /// Snapshot of all the connections currently checked out
+    pub fn checked_out(&self) -> Vec<CheckoutInfo> {
+        self.checked_out.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Forget about all checked out connections
+    pub fn clear(&self) {
+        self.checked_out.lock().unwrap().clear();
+    }
+}
@@ -1,4 +1,21 @@
+#[cfg(feature = "async")]
+pub mod async_conn;
+#[cfg(feature = "async")]
+pub mod async_pool;
 pub mod config;
 pub mod conn;
+pub mod leak;
+mod pool_core;
 pub mod poolmgr;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod resolver;
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+pub mod rustls_stream;
+pub mod socket;
+#[cfg(feature = "ssl")]
+pub mod ssl_stream;
+#[cfg(all(test, any(feature = "ssl", feature = "rustls")))]
+pub(crate) mod test_support;
+pub mod tls;
 // pub mod CustLogger;
@@ -0,0 +1,657 @@
+//! Pool policies shared by `poolmgr::ConnectionPool` and `async_pool::AsyncConnectionPool`:
+//! slot accounting, idle selection, validation schedule, the priority queue and the re-resolve
+//! and TLS reload schedules.  The pools open, validate and close the connections and do the
+//! waiting, with a `Condvar` or a tokio `Notify`
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::net::config;
+use crate::net::config::{IdleStrategy, ValidationMode};
+use crate::net::leak;
+use crate::net::proxy_protocol::ProxyHeader;
+use crate::net::resolver::{CachingResolver, Resolver, SystemResolver};
+use crate::net::socket;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::tls::TlsConnector;
+
+/// Number of idle connections `acquire` may discard before opening a new one
+const DEFAULT_VALIDATION_RETRIES: usize = 3;
+
+/// A waiter moves up one priority class after waiting this long
+const DEFAULT_PRIORITY_AGING_MS: u64 = 1_000;
+
+/// Priority class of a caller waiting in `acquire_with_priority`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Latency critical callers. They may use `Config::reserved_conns`
+    High,
+    /// Default priority
+    Normal,
+    /// Batch jobs, health checks
+    Low,
+}
+
+/// Caller queued in `acquire_with_priority`
+#[derive(Clone, Copy)]
+pub(crate) struct Waiter {
+    ticket: u64,
+    priority: Priority,
+    enqueued: Instant,
+}
+
+impl Waiter {
+    /// Priority class after aging, lower is served first
+    fn rank(&self, aging: Duration) -> u64 {
+        let steps = self.enqueued.elapsed().as_millis() / aging.as_millis().max(1);
+        (self.priority as u64).saturating_sub(steps as u64)
+    }
+}
+
+/// What the pools need to know about the connections they hold
+pub(crate) trait PooledConnection {
+    /// Id the connection is checked out under
+    fn id(&self) -> &String;
+    /// Address the connection was made to. None for Unix domain sockets
+    fn remote_addr(&self) -> Option<SocketAddr>;
+    /// PROXY header the connection sent when it was opened
+    fn proxy_header(&self) -> Option<&ProxyHeader>;
+}
+
+/// What `acquire` got from the pool
+pub(crate) enum Slot<C> {
+    /// An idle connection
+    Idle(Box<IdleConnection<C>>),
+    /// A reserved slot for a new connection. True if it is a temporary connection
+    New(bool),
+    /// An idle connection for another PROXY header to close, its slot is used for a new one
+    Replace(Box<IdleConnection<C>>),
+}
+
+/// Connection sitting in the pool
+pub(crate) struct IdleConnection<C> {
+    pub(crate) conn: C,
+    pub(crate) idle_since: Instant,
+    pub(crate) last_validated: Instant,
+}
+
+impl<C> IdleConnection<C> {
+    fn new(conn: C) -> IdleConnection<C> {
+        IdleConnection {
+            conn,
+            idle_since: Instant::now(),
+            last_validated: Instant::now(),
+        }
+    }
+}
+
+/// Book keeping for a checked out connection
+struct CheckedOut {
+    temporary: bool,
+    generation: usize,
+}
+
+/// Idle connections and counters.  Kept under a single lock so they never drift apart
+pub(crate) struct PoolState<C> {
+    idle: VecDeque<IdleConnection<C>>,
+    /// checked out connections by id
+    inuse: HashMap<String, CheckedOut>,
+    /// pooled connections checked out, being validated or being opened
+    pooled_inuse: usize,
+    /// temporary connections checked out or being opened
+    tmp_inuse: usize,
+    /// bumped by `release_all` so connections checked out before it are not pooled again
+    generation: usize,
+    /// callers waiting in `acquire_with_priority`
+    waiters: Vec<Waiter>,
+    next_ticket: u64,
+}
+
+impl<C> Default for PoolState<C> {
+    fn default() -> PoolState<C> {
+        PoolState {
+            idle: VecDeque::new(),
+            inuse: HashMap::new(),
+            pooled_inuse: 0,
+            tmp_inuse: 0,
+            generation: 0,
+            waiters: Vec::new(),
+            next_ticket: 0,
+        }
+    }
+}
+
+impl<C> PoolState<C> {
+    /// Pooled connections owned by the pool, idle or not
+    fn pooled(&self) -> usize {
+        self.idle.len() + self.pooled_inuse
+    }
+}
+
+/// Snapshot of the pool counters
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolStats {
+    /// Idle connections in the pool
+    pub idle: usize,
+    /// Pooled connections in use
+    pub pooled_inuse: usize,
+    /// Temporary connections in use. These are closed on release
+    pub temporary_inuse: usize,
+    /// Max pooled connections (idle and in use)
+    pub max_conns: usize,
+    /// Max idle connections kept on release
+    pub max_idle: usize,
+    /// Order in which idle connections are handed out
+    pub idle_strategy: IdleStrategy,
+}
+
+/// State and policies of a pool of `C` connections
+pub(crate) struct PoolCore<C> {
+    state: Mutex<PoolState<C>>,
+    min_conns: usize,
+    max_conns: usize,
+    tmp_conn_allowed: bool,
+    config: Arc<config::Config>,
+    leak_detector: Option<leak::LeakDetector>,
+    /// caches lookups between re-resolves, set if `Config::resolve_interval` is
+    resolver: Option<Arc<CachingResolver>>,
+    /// when `server` was last resolved
+    resolved: Mutex<Option<Instant>>,
+    /// when the TLS files were last checked and the connector generation the pool has seen
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    tls_checked: Mutex<(Instant, usize)>,
+}
+
+impl<C: PooledConnection> PoolCore<C> {
+    /// Pool state for a config prepared with `Config::for_pool`
+    pub(crate) fn new(
+        min_conns: usize,
+        max_conns: usize,
+        tmp_conn_allowed: bool,
+        mut config: config::Config,
+    ) -> PoolCore<C> {
+        // connects between re-resolves are served from the cache
+        let resolver = config.resolve_interval.map(|interval| {
+            let inner = config.resolver.clone().unwrap_or_else(|| Arc::new(SystemResolver));
+            Arc::new(CachingResolver::new(inner, interval))
+        });
+        if let Some(ref resolver) = resolver {
+            config.resolver = Some(resolver.clone() as Arc<dyn Resolver>);
+        }
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        let tls_generation = config.tls_connector.as_ref().map_or(0, |c| c.generation());
+        PoolCore {
+            state: Mutex::new(Default::default()),
+            min_conns,
+            max_conns,
+            tmp_conn_allowed,
+            leak_detector: config.leak_detection_threshold.map(|threshold| {
+                leak::LeakDetector::new(threshold, config.leak_detection_backtrace.unwrap_or(false))
+            }),
+            config: Arc::new(config),
+            resolver,
+            resolved: Mutex::new(None),
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            tls_checked: Mutex::new((Instant::now(), tls_generation)),
+        }
+    }
+
+    /// Config the pool's connections are made with
+    pub(crate) fn config(&self) -> &Arc<config::Config> {
+        &self.config
+    }
+
+    /// Lock the state, for the pools waiting on it
+    pub(crate) fn lock(&self) -> MutexGuard<'_, PoolState<C>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Number of idle connections
+    #[cfg(test)]
+    pub(crate) fn idle_count(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /// Max idle connections kept on release. Defaults to max pool size
+    fn max_idle(&self) -> usize {
+        self.config.max_idle.unwrap_or(self.max_conns)
+    }
+
+    /// Idle selection strategy from the config
+    fn idle_strategy(&self) -> IdleStrategy {
+        self.config.idle_strategy.unwrap_or(IdleStrategy::Fifo)
+    }
+
+    /// Snapshot of the pool counters
+    pub(crate) fn stats(&self) -> PoolStats {
+        let state = self.lock();
+        PoolStats {
+            idle: state.idle.len(),
+            pooled_inuse: state.pooled_inuse,
+            temporary_inuse: state.tmp_inuse,
+            max_conns: self.max_conns,
+            max_idle: self.max_idle(),
+            idle_strategy: self.idle_strategy(),
+        }
+    }
+
+    /// Connections currently checked out of the pool.  Empty unless leak detection is enabled
+    pub(crate) fn checked_out(&self) -> Vec<leak::CheckoutInfo> {
+        match self.leak_detector {
+            Some(ref detector) => detector.checked_out(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Log a warning for connections held longer than `Config::leak_detection_threshold`
+    pub(crate) fn check_leaks(&self) -> usize {
+        match self.leak_detector {
+            Some(ref detector) => detector.check(),
+            None => 0,
+        }
+    }
+
+    /// Min pool size, the number of connections `init` opens
+    pub(crate) fn min_conns(&self) -> usize {
+        self.min_conns
+    }
+
+    /// Reserve a slot for a connection `init` opens. False if the pool is full
+    pub(crate) fn reserve_init(&self) -> bool {
+        let mut state = self.lock();
+        if state.pooled() >= self.max_conns {
+            return false;
+        }
+        state.pooled_inuse += 1;
+        true
+    }
+
+    /// Put a connection `init` opened in a reserved slot into the pool
+    pub(crate) fn add_idle(&self, conn: C) {
+        let mut state = self.lock();
+        state.pooled_inuse -= 1;
+        state.idle.push_back(IdleConnection::new(conn));
+    }
+
+    /// Give back a slot reserved for a new connection or held by an idle connection which
+    /// failed validation
+    pub(crate) fn cancel(&self, temporary: bool) {
+        let mut state = self.lock();
+        if temporary {
+            state.tmp_inuse -= 1;
+        } else {
+            state.pooled_inuse -= 1;
+        }
+    }
+
+    /// Take the idle connections unused for longer than `Config::idle_timeout`, keeping at
+    /// least the min pool size
+    pub(crate) fn take_expired(&self) -> Vec<IdleConnection<C>> {
+        let timeout = match self.config.idle_timeout {
+            Some(timeout) => timeout,
+            None => return Vec::new(),
+        };
+        let expired = {
+            let mut state = self.lock();
+            let mut expired = Vec::new();
+            let mut i = 0;
+            while i < state.idle.len() && state.pooled() > self.min_conns {
+                if state.idle[i].idle_since.elapsed() >= timeout {
+                    expired.extend(state.idle.remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            expired
+        };
+        for idle in &expired {
+            info!(
+                "trim_idle(): conn id:{} idle for {:?}. Closing it",
+                idle.conn.id(),
+                idle.idle_since.elapsed()
+            );
+        }
+        expired
+    }
+
+    /// Take all the idle connections. Connections currently checked out are closed when they
+    /// are released
+    pub(crate) fn release_all(&self) -> Vec<IdleConnection<C>> {
+        let mut state = self.lock();
+        state.generation += 1;
+        let idle: Vec<IdleConnection<C>> = state.idle.drain(..).collect();
+        info!(
+            "release_all called: pooled in use: {}, temporary in use: {}",
+            state.pooled_inuse,
+            state.tmp_inuse
+        );
+        idle
+    }
+
+    /// Validation mode from the config
+    pub(crate) fn validation_mode(&self) -> ValidationMode {
+        self.config.validation.unwrap_or(ValidationMode::None)
+    }
+
+    /// How many idle connections `acquire` may discard before it opens a new one
+    pub(crate) fn validation_retries(&self) -> usize {
+        self.config.validation_retries.unwrap_or(DEFAULT_VALIDATION_RETRIES)
+    }
+
+    /// Does an idle connection need validating before it is handed out
+    pub(crate) fn validate_on_borrow(&self, idle: &IdleConnection<C>) -> bool {
+        match self.validation_mode() {
+            ValidationMode::OnBorrow => true,
+            ValidationMode::WhileIdle(interval) => idle.last_validated.elapsed() >= interval,
+            _ => false,
+        }
+    }
+
+    /// Take the idle connections which have not been checked for the `ValidationMode::WhileIdle`
+    /// interval, with the generation to give to `return_validated`
+    pub(crate) fn take_unvalidated(&self) -> (Vec<IdleConnection<C>>, usize) {
+        let interval = match self.validation_mode() {
+            ValidationMode::WhileIdle(interval) => interval,
+            _ => return (Vec::new(), 0),
+        };
+        let mut state = self.lock();
+        let (stale, fresh): (VecDeque<IdleConnection<C>>, VecDeque<IdleConnection<C>>) = state
+            .idle
+            .drain(..)
+            .partition(|idle| idle.last_validated.elapsed() >= interval);
+        state.idle = fresh;
+        state.pooled_inuse += stale.len();
+        (stale.into_iter().collect(), state.generation)
+    }
+
+    /// Put back a connection from `take_unvalidated` if it passed validation. Returns the
+    /// connection to close otherwise
+    pub(crate) fn return_validated(
+        &self,
+        mut idle: IdleConnection<C>,
+        is_valid: bool,
+        generation: usize,
+    ) -> Option<IdleConnection<C>> {
+        let mut state = self.lock();
+        state.pooled_inuse -= 1;
+        if is_valid && state.generation == generation {
+            idle.last_validated = Instant::now();
+            state.idle.push_back(idle);
+            None
+        } else {
+            info!("validate_idle(): conn id:{} failed validation. Dropping", idle.conn.id());
+            Some(idle)
+        }
+    }
+
+    /// Mark a connection as checked out
+    pub(crate) fn checkout(&self, conn: &C, temporary: bool) {
+        {
+            let mut state = self.lock();
+            let generation = state.generation;
+            state.inuse.insert(
+                conn.id().clone(),
+                CheckedOut {
+                    temporary,
+                    generation,
+                },
+            );
+        }
+        if let Some(ref detector) = self.leak_detector {
+            detector.on_acquire(conn.id());
+            detector.check();
+        }
+    }
+
+    /// Return a checked out connection to the pool.  It is kept if `keep` is true, it is a pooled
+    /// connection and there are less than `max_idle` idle connections.  Otherwise it is
+    /// returned for the caller to close outside of the lock
+    pub(crate) fn checkin(&self, conn: C, keep: bool) -> Option<C> {
+        if let Some(ref detector) = self.leak_detector {
+            detector.on_release(conn.id());
+        }
+        let id = conn.id().clone();
+        let max_idle = self.max_idle();
+        let mut state = self.lock();
+        let closed = match state.inuse.remove(&id) {
+            None => {
+                warn!("conn id:{} was not checked out from this pool. Closing it", id);
+                Some(conn)
+            }
+            Some(ref c) if c.temporary => {
+                state.tmp_inuse -= 1;
+                info!("conn id:{} is a temporary connection. Closing it", id);
+                Some(conn)
+            }
+            Some(c) => {
+                state.pooled_inuse -= 1;
+                if keep && c.generation == state.generation && state.idle.len() < max_idle {
+                    info!("Pushing back to ideal_conns");
+                    state.idle.push_back(IdleConnection::new(conn));
+                    None
+                } else {
+                    info!("conn id:{}:It should trigger drop connection from inuse", id);
+                    Some(conn)
+                }
+            }
+        };
+        info!(
+            "checkin(): conn id:{}, max_idle:{}, idle connection: {}, pooled in use:{}, temporary in use: {}",
+            id,
+            max_idle,
+            state.idle.len(),
+            state.pooled_inuse,
+            state.tmp_inuse
+        );
+        closed
+    }
+
+    /// Take an idle connection or reserve a slot for a new one. Callers which do not wait in
+    /// the queue (`waiting` false) do not get pooled connections while others are waiting.
+    /// Only idle connections which sent the same PROXY header (`Config::proxy_protocol_header`
+    /// unless `header` is given) are handed out
+    pub(crate) fn take_slot(
+        &self,
+        state: &mut PoolState<C>,
+        priority: Priority,
+        waiting: bool,
+        use_idle: bool,
+        header: Option<&ProxyHeader>,
+    ) -> Option<Slot<C>> {
+        if (waiting || state.waiters.is_empty()) && self.can_take_pooled(state, priority) {
+            let header = header.or(self.config.proxy_protocol_header.as_ref());
+            if use_idle {
+                let matching = |idle: &IdleConnection<C>| idle.conn.proxy_header() == header;
+                let pos = match self.idle_strategy() {
+                    IdleStrategy::Fifo => state.idle.iter().position(matching),
+                    IdleStrategy::Lifo => state.idle.iter().rposition(matching),
+                };
+                if let Some(idle) = pos.and_then(|pos| state.idle.remove(pos)) {
+                    state.pooled_inuse += 1;
+                    return Some(Slot::Idle(Box::new(idle)));
+                }
+            }
+            if state.pooled() < self.max_conns {
+                state.pooled_inuse += 1;
+                return Some(Slot::New(false));
+            }
+            // the pool is full of idle connections for other headers
+            if let Some(idle) = state.idle.pop_front() {
+                state.pooled_inuse += 1;
+                return Some(Slot::Replace(Box::new(idle)));
+            }
+        }
+        if !waiting && self.tmp_conn_allowed {
+            state.tmp_inuse += 1;
+            return Some(Slot::New(true));
+        }
+        None
+    }
+
+    /// Can a caller with this priority use a pooled connection. Only `Priority::High`
+    /// callers may use the `Config::reserved_conns` slice of the pool
+    fn can_take_pooled(&self, state: &PoolState<C>, priority: Priority) -> bool {
+        let reserved = if priority == Priority::High {
+            0
+        } else {
+            self.config.reserved_conns.unwrap_or(0)
+        };
+        state.pooled_inuse + reserved < self.max_conns &&
+            (!state.idle.is_empty() || state.pooled() < self.max_conns)
+    }
+
+    /// A caller of `acquire_with_priority`. It keeps its place in the queue when it waits again
+    /// after an idle connection failed validation
+    pub(crate) fn new_waiter(&self, priority: Priority) -> Waiter {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        Waiter {
+            ticket,
+            priority,
+            enqueued: Instant::now(),
+        }
+    }
+
+    /// Queue up a waiter
+    pub(crate) fn enqueue(&self, state: &mut PoolState<C>, waiter: Waiter) {
+        state.waiters.push(waiter);
+    }
+
+    /// Remove a waiter from the queue, when it gave up
+    pub(crate) fn dequeue(&self, state: &mut PoolState<C>, waiter: Waiter) {
+        state.waiters.retain(|w| w.ticket != waiter.ticket);
+    }
+
+    /// Take a slot for a queued waiter if it is the first one in priority order which can be
+    /// served, and remove it from the queue. Waiters move up one priority class every
+    /// `Config::priority_aging`
+    pub(crate) fn take_slot_if_first(
+        &self,
+        state: &mut PoolState<C>,
+        waiter: Waiter,
+        use_idle: bool,
+    ) -> Option<Slot<C>> {
+        let aging = self.config.priority_aging.unwrap_or(
+            Duration::from_millis(DEFAULT_PRIORITY_AGING_MS),
+        );
+        let first = {
+            let mut waiters: Vec<&Waiter> = state.waiters.iter().collect();
+            waiters.sort_by_key(|w| (w.rank(aging), w.ticket));
+            waiters
+                .into_iter()
+                .find(|w| self.can_take_pooled(state, w.priority))
+                .map(|w| w.ticket)
+        };
+        if first != Some(waiter.ticket) {
+            return None;
+        }
+        let slot = self.take_slot(state, waiter.priority, true, use_idle, None);
+        if slot.is_some() {
+            self.dequeue(state, waiter);
+        }
+        slot
+    }
+
+    /// Does the pool follow DNS changes of `server`. Not for Unix domain sockets and through a
+    /// `Config::proxy`, whose connections report the proxy's address
+    pub(crate) fn follows_dns(&self) -> bool {
+        socket::unix_path(&self.config).is_none() && self.config.proxy.is_none()
+    }
+
+    /// Start a re-resolve: the cached answer for `server` is dropped and the next one is
+    /// scheduled `Config::resolve_interval` from now
+    pub(crate) fn start_resolve(&self) {
+        *self.resolved.lock().unwrap() = Some(Instant::now());
+        if let Some(ref resolver) = self.resolver {
+            resolver.invalidate(&self.config.server.clone().unwrap(), self.config.port.unwrap());
+        }
+    }
+
+    /// Is a re-resolve due. Claims it, so concurrent callers keep using the current addresses
+    /// while the first one resolves
+    pub(crate) fn claim_resolve(&self) -> bool {
+        let interval = match self.config.resolve_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let mut resolved = self.resolved.lock().unwrap();
+        let due = match *resolved {
+            Some(at) => at.elapsed() >= interval,
+            None => true,
+        };
+        if due {
+            *resolved = Some(Instant::now());
+        }
+        due
+    }
+
+    /// Take the idle connections to addresses `server` no longer resolves to
+    pub(crate) fn take_stale(&self, addrs: &[SocketAddr]) -> Vec<IdleConnection<C>> {
+        let stale: Vec<IdleConnection<C>> = {
+            let mut state = self.lock();
+            let (stale, fresh): (VecDeque<IdleConnection<C>>, VecDeque<IdleConnection<C>>) = state
+                .idle
+                .drain(..)
+                .partition(|idle| idle.conn.remote_addr().is_some_and(|a| !addrs.contains(&a)));
+            state.idle = fresh;
+            stale.into_iter().collect()
+        };
+        for idle in &stale {
+            info!(
+                "refresh_addresses(): conn id:{} is connected to {} which {} no longer resolves to. Closing it",
+                idle.conn.id(),
+                idle.conn.remote_addr().unwrap(),
+                self.config.server.clone().unwrap()
+            );
+        }
+        stale
+    }
+
+    /// The shared SSL connector
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub(crate) fn tls_connector(&self) -> Option<&TlsConnector> {
+        self.config.tls_connector.as_ref()
+    }
+
+    /// Start a check of the TLS files, the next one is scheduled `Config::tls_reload_interval`
+    /// from now
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub(crate) fn start_tls_check(&self) {
+        self.tls_checked.lock().unwrap().0 = Instant::now();
+    }
+
+    /// Is a check of the TLS files due. Claims it, so only one caller reads the files
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub(crate) fn claim_tls_check(&self) -> bool {
+        let interval = match self.config.tls_reload_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let mut checked = self.tls_checked.lock().unwrap();
+        let due = checked.0.elapsed() >= interval;
+        if due {
+            checked.0 = Instant::now();
+        }
+        due
+    }
+
+    /// Has the connector been rebuilt since the last time and the connections made with the
+    /// old one are to be closed (`Config::tls_recycle_on_reload`)
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub(crate) fn tls_recycle_due(&self) -> bool {
+        let generation = match self.config.tls_connector {
+            Some(ref connector) => connector.generation(),
+            None => return false,
+        };
+        let changed = {
+            let mut checked = self.tls_checked.lock().unwrap();
+            let changed = checked.1 != generation;
+            checked.1 = generation;
+            changed
+        };
+        changed && self.config.tls_recycle_on_reload.unwrap_or(false)
+    }
+}
@@ -1,25 +1,42 @@
 //! Connection Pool.
 
-use std::collections::VecDeque;
 use std::io::{Result, Error, ErrorKind};
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Condvar;
 use std::default::Default;
+use std::time::{Duration, Instant};
+
+
+use crate::net::conn;
+use crate::net::config;
+use crate::net::config::ValidationMode;
+use crate::net::leak;
+use crate::net::pool_core::{PoolCore, PooledConnection, Slot, Waiter};
+pub use crate::net::pool_core::{PoolStats, Priority};
+use crate::net::proxy_protocol::ProxyHeader;
+use crate::net::socket;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::tls::TlsConnector;
+
+impl PooledConnection for conn::Connection {
+    fn id(&self) -> &String {
+        conn::Connection::id(self)
+    }
 
+    fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+        conn::Connection::remote_addr(self)
+    }
 
-use net::conn;
-use net::config;
-
+    fn proxy_header(&self) -> Option<&ProxyHeader> {
+        conn::Connection::proxy_header(self)
+    }
+}
 
 /// ConnectionPool which provide pooling capability for Connection objects
 /// It has support for max number of connections with temporary allowable connections
 pub struct ConnectionPool {
-    idle_conns: Mutex<VecDeque<conn::Connection>>,
-    min_conns: usize,
-    max_conns: usize,
-    tmp_conn_allowed: bool,
-    config: config::Config,
-    conns_inuse: AtomicUsize,
+    core: PoolCore<conn::Connection>,
+    /// signalled whenever a connection or a slot becomes available
+    available: Condvar,
 }
 
 /// Default implementation for  ConnectionPool
@@ -27,200 +44,404 @@ impl Default for ConnectionPool {
     fn default() -> ConnectionPool {
 
         ConnectionPool {
-            idle_conns: Mutex::new(VecDeque::new()),
-            // idle_conns: VecDeque::new(),
-            min_conns: 0,
-            max_conns: 10,
-            tmp_conn_allowed: true,
-            config: Default::default(),
-            conns_inuse: AtomicUsize::new(0),
+            core: PoolCore::new(0, 10, true, Default::default()),
+            available: Condvar::new(),
         }
     }
 }
 
 /// Connection pool implementation
 impl ConnectionPool {
-    /// New instance
+    /// New instance with the settings checked and, if SSL is used, the SSL connector built.
+    /// Fails for invalid settings or unreadable certificate, key or CA files
     pub fn new(
         pool_min_size: usize,
         pool_max_size: usize,
         tmp_allowed: bool,
         conn_config: &config::Config,
-    ) -> ConnectionPool {
-        ConnectionPool {
-            idle_conns: Mutex::new(VecDeque::new()),
-            min_conns: pool_min_size,
-            max_conns: pool_max_size,
-            tmp_conn_allowed: tmp_allowed,
-            config: conn_config.clone(),
-            conns_inuse: AtomicUsize::new(0),
-        }
+    ) -> Result<ConnectionPool> {
+        let config = conn_config.for_pool()?;
+        Ok(ConnectionPool {
+            core: PoolCore::new(pool_min_size, pool_max_size, tmp_allowed, config),
+            available: Condvar::new(),
+        })
     }
+
     #[cfg(test)]
     pub fn idle_conns_count(&self) -> usize {
-        self.idle_conns.lock().unwrap().len()
+        self.core.idle_count()
+
+    }
+
+    /// Snapshot of the pool counters
+    pub fn stats(&self) -> PoolStats {
+        self.core.stats()
+    }
+
+    /// Close idle connections unused for longer than `Config::idle_timeout`, keeping at least
+    /// the min pool size.  Call it periodically. Returns the number of closed connections
+    pub fn trim_idle(&self) -> usize {
+        let expired = self.core.take_expired();
+        if !expired.is_empty() {
+            self.available.notify_all();
+        }
+        expired.len()
+    }
+
+    /// Resolve `server` again, bypassing the cache, and close the idle connections to addresses
+    /// it no longer resolves to.  `acquire` calls it every `Config::resolve_interval`.
+    /// Does nothing for Unix domain sockets and through a `Config::proxy`, whose connections
+    /// report the proxy's address. Returns the number of closed connections
+    pub fn refresh_addresses(&self) -> Result<usize> {
+        if !self.core.follows_dns() {
+            return Ok(0);
+        }
+        self.core.start_resolve();
+        let addrs = socket::resolve(self.core.config())?;
+        let stale = self.core.take_stale(&addrs);
+        if !stale.is_empty() {
+            self.available.notify_all();
+        }
+        Ok(stale.len())
+    }
 
+    /// Re-resolve if `Config::resolve_interval` has passed since the last time. Only the first
+    /// caller to see it resolves, the others go on with the current addresses
+    fn refresh_addresses_if_due(&self) {
+        if self.core.claim_resolve() {
+            if let Err(e) = self.refresh_addresses() {
+                // keep the connections we have, the lookup is retried on the next acquire
+                warn!("Failed to resolve {}: {}", self.core.config().server.clone().unwrap(), e);
+            }
+        }
+    }
+
+    /// The shared SSL connector. Push new certificates with `TlsConnector::set_identity` and
+    /// `TlsConnector::set_trust_anchors`
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn tls_connector(&self) -> Option<&TlsConnector> {
+        self.core.tls_connector()
+    }
+
+    /// Rebuild the SSL connector if the certificate, key or CA files changed. With
+    /// `Config::tls_recycle_on_reload` the connections made with the old connector are closed.
+    /// `acquire` calls it every `Config::tls_reload_interval`. Returns true if it was rebuilt
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub fn reload_tls(&self) -> Result<bool> {
+        let connector = match self.core.tls_connector() {
+            Some(connector) => connector,
+            None => return Ok(false),
+        };
+        self.core.start_tls_check();
+        let reloaded = connector.reload_if_changed();
+        self.recycle_if_tls_changed();
+        reloaded
     }
+
+    /// Check the TLS files if `Config::tls_reload_interval` has passed, and recycle the
+    /// connections if the connector was rebuilt since the last time, including by a push
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    fn reload_tls_if_due(&self) {
+        if self.core.claim_tls_check() {
+            if let Err(e) = self.reload_tls() {
+                // keep the old connector, the files are checked again on the next acquire
+                warn!("Failed to reload the TLS certificates: {}", e);
+            }
+        } else {
+            self.recycle_if_tls_changed();
+        }
+    }
+
+    /// Close the connections of older connector generations if `Config::tls_recycle_on_reload`
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    fn recycle_if_tls_changed(&self) {
+        if self.core.tls_recycle_due() {
+            info!("TLS connector changed, recycling the connections");
+            self.release_all();
+        }
+    }
+
     /// Initial the connection pool
     pub fn init(&self) -> bool {
-        self.idle_conns.lock().unwrap().reserve(self.max_conns);
-        for i in 0..self.min_conns {
+        for i in 0..self.core.min_conns() {
+            if !self.core.reserve_init() {
+                break;
+            }
             info!("*****Init:Creating connection {}", i);
-            let conn = conn::Connection::connect(&self.config);
-
-            let host: &str = &self.config.server.clone().unwrap();
-            let port = &self.config.port.unwrap();
-
+            let conn = conn::Connection::connect(self.core.config());
+            let endpoint = socket::endpoint(self.core.config());
             match conn {
                 Ok(c) => {
                     let id = c.id().clone();
-                    self.idle_conns.lock().unwrap().push_back(c);
-                    info!(
-                        "Connection id:{}, Connecting to server {}:{}",
-                        id,
-                        host,
-                        port
-                    );
+                    self.core.add_idle(c);
+                    self.available.notify_all();
+                    info!("Connection id:{}, Connecting to server {}", id, endpoint);
                 }
                 Err(e) => {
-
-                    error!(
-                        "Failed to create a connection to {}:{}. Error: {}",
-                        host,
-                        port,
-                        e
-                    );
+                    self.core.cancel(false);
+                    self.available.notify_all();
+                    error!("Failed to create a connection to {}. Error: {}", endpoint, e);
                     return false;
                 }
             }
         }
-        return true;
+        true
     }
 
     /// Release all :  Remove all connections  from th pool
+    /// Connections currently checked out are closed when they are released
     pub fn release_all(&self) {
         info!("release_all called");
         info!("It should trigger drop connection");
-        self.idle_conns.lock().unwrap().clear();
-        self.conns_inuse.store(0, Ordering::Relaxed);
-        let total_count = self.idle_conns.lock().unwrap().len() +
-            self.conns_inuse.load(Ordering::Relaxed);
-        info!("release_all called: Total_count: {}", total_count);
+        let idle = self.core.release_all();
+        self.available.notify_all();
+        drop(idle);
     }
 
 
 
-    ///Releae connection
-    #[allow(dead_code)]
-    pub fn release(&self, conn: conn::Connection) {
-        let a = self.idle_conns.lock();
-        let conn_inuse = self.conns_inuse.load(Ordering::Relaxed);
-        let id = conn.id().clone();
-        let is_valid = conn.is_valid();
+    /// Connections currently checked out of the pool.  Empty unless leak detection is enabled
+    pub fn checked_out(&self) -> Vec<leak::CheckoutInfo> {
+        self.core.checked_out()
+    }
 
-        let idle_count = a.unwrap().len();
-        let total = idle_count + conn_inuse;
+    /// Log a warning for connections held longer than `Config::leak_detection_threshold`.
+    /// This is also done on every acquire. Returns the number of newly reported connections
+    pub fn check_leaks(&self) -> usize {
+        self.core.check_leaks()
+    }
+
+    /// Check the connection with `Connection::is_valid` and the configured probe
+    fn validate(&self, conn: &mut conn::Connection) -> bool {
+        if !conn.is_valid() {
+            return false;
+        }
+        match self.core.config().validation_probe {
+            Some(ref probe) => probe(conn),
+            None => true,
+        }
+    }
 
-        info!(
-            "release(): conn id:{}, min_conn:{}, idle connection: {}, connection in use:{},  total: {}",
-            id,
-            self.min_conns,
-            idle_count,
-            conn_inuse,
-            total
-        );
 
-        if total < self.min_conns && is_valid {
-            info!("Pushing back to ideal_conns");
-            self.idle_conns.lock().unwrap().push_back(conn);
-            self.conns_inuse.fetch_sub(1, Ordering::Relaxed);
-            return;
+    /// Validate idle connections which have not been checked for `ValidationMode::WhileIdle` interval.
+    /// Connections failing validation are dropped.  Call it periodically. Returns the number of dropped connections
+    pub fn validate_idle(&self) -> usize {
+        let (stale, generation) = self.core.take_unvalidated();
+        if stale.is_empty() {
+            return 0;
         }
+        let mut dropped = 0;
+        for mut idle in stale {
+            let is_valid = self.validate(&mut idle.conn);
+            if self.core.return_validated(idle, is_valid, generation).is_some() {
+                dropped += 1;
+            }
+        }
+        self.available.notify_all();
+        dropped
+    }
+
+    /// Return a checked out connection to the pool.  It is kept if `keep` is true, it is a pooled
+    /// connection and there are less than `max_idle` idle connections.  Otherwise it is closed
+    fn checkin(&self, conn: conn::Connection, keep: bool) {
+        let closed = self.core.checkin(conn, keep);
+        self.available.notify_all();
+        // close outside of the lock
+        drop(closed);
+    }
+
+    ///Releae connection
+    pub fn release(&self, mut conn: conn::Connection) {
+        let is_valid = if self.core.validation_mode() == ValidationMode::OnReturn {
+            self.validate(&mut conn)
+        } else {
+            conn.is_valid()
+        };
         if !is_valid {
-            self.conns_inuse.fetch_sub(1, Ordering::Relaxed);
             info!("Connection not valid. It should trigger drop connection");
-        } else {
-            //drop(conn);
-            self.conns_inuse.fetch_sub(1, Ordering::Relaxed);
-            info!(
-                "conn id:{}:It should trigger drop connection from inuse",
-                id
-            );
         }
-        info!(
-            "release() end: Total_count: {}",
-            self.idle_conns.lock().unwrap().len() + self.conns_inuse.load(Ordering::Relaxed)
-        );
-
-        let _ = a;
+        self.checkin(conn, is_valid);
     }
 
     /// Drop connection.  Use only if disconect.
-    #[allow(unused_variables)]
     pub fn drop(&self, conn: conn::Connection) {
-        self.conns_inuse.fetch_sub(1, Ordering::Relaxed);
-        warn!(
-            "drop() end: Total_count: {}",
-            self.idle_conns.lock().unwrap().len() + self.conns_inuse.load(Ordering::Relaxed)
-        );
-
+        self.checkin(conn, false);
     }
 
+    /// Turn a slot into a connection.  Returns None if the idle connection failed validation.
+    /// New connections send `header` if given
+    fn open_slot(
+        &self,
+        slot: Slot<conn::Connection>,
+        header: Option<&ProxyHeader>,
+    ) -> Result<Option<conn::Connection>> {
+        match slot {
+            Slot::Replace(idle) => {
+                info!(
+                    "acquire(): closing idle conn id:{} to make room for another PROXY header",
+                    idle.conn.id()
+                );
+                drop(idle);
+                self.open_slot(Slot::New(false), header)
+            }
+            Slot::Idle(mut idle) => {
+                if self.core.validate_on_borrow(&idle) && !self.validate(&mut idle.conn) {
+                    info!("acquire(): conn id:{} failed validation. Dropping", idle.conn.id());
+                    self.core.cancel(false);
+                    self.available.notify_all();
+                    return Ok(None);
+                }
+                self.core.checkout(&idle.conn, false);
+                Ok(Some(idle.conn))
+            }
+            Slot::New(temporary) => {
+                info!("*****Init:Creating connection..");
+                let conn = match header {
+                    Some(header) => conn::Connection::connect_with_proxy_header(
+                        self.core.config(),
+                        header.clone(),
+                    ),
+                    None => conn::Connection::connect(self.core.config()),
+                };
+                match conn {
+                    Ok(c) => {
+                        info!("New connection id:{}, temporary: {}", c.id().clone(), temporary);
+                        self.core.checkout(&c, temporary);
+                        Ok(Some(c))
+                    }
+                    Err(e) => {
+                        error!("Failed to create a connection : {}", e);
+                        self.core.cancel(temporary);
+                        self.available.notify_all();
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
 
     /// Aquire Connection
+    /// Idle connections failing validation are dropped and replaced, up to `Config::validation_retries`
     pub fn acquire(&self) -> Result<conn::Connection> {
+        self.acquire_matching(None)
+    }
 
-        let mut conns = self.idle_conns.lock().unwrap();
-        {
-            if !conns.is_empty() {
-                let result = conns.pop_front();
-                if result.is_some() {
-                    let conn = result.unwrap();
-                    // self.inuse_conns.push_back(conn);
-                    self.conns_inuse.fetch_add(1, Ordering::Relaxed);
-                    return Ok(conn);
+    /// Aquire a connection which announced `header` with the PROXY protocol when it was opened.
+    /// An idle connection for the same header is reused, otherwise a new one is opened
+    pub fn acquire_with_proxy_header(&self, header: &ProxyHeader) -> Result<conn::Connection> {
+        self.acquire_matching(Some(header))
+    }
+
+    /// `acquire` for connections with the given PROXY header
+    fn acquire_matching(&self, header: Option<&ProxyHeader>) -> Result<conn::Connection> {
+        self.refresh_addresses_if_due();
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        self.reload_tls_if_due();
+        let mut retries = self.core.validation_retries();
+        let mut use_idle = true;
+        loop {
+            let slot = {
+                let mut state = self.core.lock();
+                self.core.take_slot(&mut state, Priority::Normal, false, use_idle, header)
+            };
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    return Err(Error::other(
+                        "Max pool size has reached and temporary connections are not allowed.",
+                    ));
                 }
+            };
+            if let Some(c) = self.open_slot(slot, header)? {
+                return Ok(c);
             }
-            info!("Allocating new connection");
-            let total_count = conns.len() + self.conns_inuse.load(Ordering::Relaxed);
-            if total_count >= self.max_conns && self.tmp_conn_allowed == false {
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    // desc: "No connection available",
-                    "Max pool size has reached and temporary connections are \
-                                       not allowed."
-                        .to_string(),
-                ));
-
+            if retries == 0 {
+                use_idle = false;
+            } else {
+                retries -= 1;
             }
         }
-        info!("*****Init:Creating connection..");
-        let conn = conn::Connection::connect(&self.config);
-        match conn {
-            Ok(c) => {
-                info!("New connection id:{}", c.id().clone());
-                self.conns_inuse.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Aquire Connection, waiting up to `timeout` for one to be released if the pool is exhausted.
+    /// Waiters are served by priority, a waiter moves up one priority class every
+    /// `Config::priority_aging` so low priority callers are not starved. Temporary connections are not used
+    pub fn acquire_with_priority(
+        &self,
+        priority: Priority,
+        timeout: Duration,
+    ) -> Result<conn::Connection> {
+        self.refresh_addresses_if_due();
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        self.reload_tls_if_due();
+        let deadline = Instant::now() + timeout;
+        let mut retries = self.core.validation_retries();
+        let mut use_idle = true;
+        let waiter = self.core.new_waiter(priority);
+        loop {
+            let slot = self.wait_for_slot(waiter, deadline, use_idle)?;
+            if let Some(c) = self.open_slot(slot, None)? {
                 return Ok(c);
             }
-            Err(e) => {
-                error!("Failed to create a connection : {}", e);
-                return Err(e);
+            if retries == 0 {
+                use_idle = false;
+            } else {
+                retries -= 1;
             }
         }
+    }
 
+    /// Queue up and wait until this waiter is the first one in priority order which can be served
+    fn wait_for_slot(
+        &self,
+        waiter: Waiter,
+        deadline: Instant,
+        use_idle: bool,
+    ) -> Result<Slot<conn::Connection>> {
+        let mut state = self.core.lock();
+        self.core.enqueue(&mut state, waiter);
+        loop {
+            if let Some(slot) = self.core.take_slot_if_first(&mut state, waiter, use_idle) {
+                drop(state);
+                // the next waiter may be able to go as well
+                self.available.notify_all();
+                return Ok(slot);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                self.core.dequeue(&mut state, waiter);
+                drop(state);
+                self.available.notify_all();
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for a connection from the pool".to_string(),
+                ));
+            }
+            state = self.available.wait_timeout(state, deadline - now).unwrap().0;
+        }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
     use std::io::prelude::*;
-    use std::net::{TcpListener, TcpStream};
+    use std::net::TcpListener;
     // use std::default::Default;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
     use std::thread;
-    use net::config;
+    use crate::net::config;
+    use crate::net::conn;
+    use crate::net::resolver::tests::TestResolver;
     use std::str;
+    use std::env;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::process;
+    use std::io::BufReader;
     // use std::io::{Read, Write};
     // use std::old_io;
     // use std::test;
@@ -236,7 +457,13 @@ pub mod tests {
     pub fn listen_ip4_localhost(port: u16, rx: Receiver<isize>) {
         let uri = format!("127.0.0.1:{}", port);
         let acceptor = TcpListener::bind(&*uri).unwrap();
+        serve(acceptor, rx);
+    }
 
+    /// Echo server on an already bound listener
+    #[cfg(test)]
+    pub fn serve(acceptor: TcpListener, rx: Receiver<isize>) {
+        let port = acceptor.local_addr().unwrap().port();
         // acceptor.set_timeout(Some(1000));
         for stream in acceptor.incoming() {
 
@@ -266,7 +493,7 @@ pub mod tests {
     }
     #[cfg(test)]
     #[allow(unused_variables)]
-    fn handle_client(mut stream: TcpStream) -> () {
+    fn handle_client<S: Read + Write>(mut stream: S) -> () {
 
         let mut buf = [0];
         loop {
@@ -305,7 +532,7 @@ pub mod tests {
         info!("test_new started---------");
 
         let cfg: config::Config = Default::default();
-        let pool = super::ConnectionPool::new(0, 5, false, &cfg);
+        let pool = super::ConnectionPool::new(0, 5, false, &cfg).unwrap();
         assert_eq!(pool.idle_conns_count(), 0);
         sleep(Duration::from_millis(1000));
         pool.release_all();
@@ -324,7 +551,7 @@ pub mod tests {
         cfg.port = Some(80); //Some(old_io::test::next_test_port());
         cfg.server = Some("google.com".to_string()); //Some("127.0.0.1".to_string());
 
-        let pool = super::ConnectionPool::new(2, 20, true, &cfg);
+        let pool = super::ConnectionPool::new(2, 20, true, &cfg).unwrap();
         assert_eq!(pool.init(), true);
         assert_eq!(pool.idle_conns_count(), 2);
         let mut conn = pool.acquire().unwrap();
@@ -338,7 +565,7 @@ pub mod tests {
             println!("Received {}", buffer);
         }
         pool.release(conn);
-        assert_eq!(pool.idle_conns_count(), 1);
+        assert_eq!(pool.idle_conns_count(), 2);
         info!("test_lib ended---------");
 
     }
@@ -368,7 +595,7 @@ pub mod tests {
             });
             sleep(Duration::from_millis(500));
             info!("test_init starting connection pool");
-            let pool = super::ConnectionPool::new(1, 5, false, &cfg);
+            let pool = super::ConnectionPool::new(1, 5, false, &cfg).unwrap();
             assert_eq!(pool.init(), true);
             assert_eq!(pool.idle_conns_count(), 1);
             let mut c1 = pool.acquire().unwrap();
@@ -406,8 +633,9 @@ pub mod tests {
         let listen_port = cfg.port.unwrap();
         let (tx, rx): (Sender<isize>, Receiver<isize>) = channel();
         thread::spawn(move || { listen_ip4_localhost(listen_port, rx); });
+        sleep(Duration::from_millis(500));
 
-        let pool = super::ConnectionPool::new(2, 5, true, &cfg);
+        let pool = super::ConnectionPool::new(2, 5, true, &cfg).unwrap();
         let pool_shared = Arc::new(pool);
         let mut ts = Vec::new();
 
@@ -440,7 +668,10 @@ pub mod tests {
             t.unwrap().join();
         }
         sleep(Duration::from_millis(500));
-        assert_eq!(pool_shared.idle_conns_count(), 1);
+        let stats = pool_shared.stats();
+        assert!(stats.idle >= 1 && stats.idle <= 5);
+        assert_eq!(stats.pooled_inuse, 0);
+        assert_eq!(stats.temporary_inuse, 0);
         pool_shared.release_all();
         assert_eq!(pool_shared.idle_conns_count(), 0);
         tx.send(0);
@@ -461,7 +692,7 @@ pub mod tests {
         thread::spawn(move || { listen_ip4_localhost(listen_port, rx); });
         sleep(Duration::from_millis(1000));
         {
-            let pool = super::ConnectionPool::new(2, 2, true, &cfg);
+            let pool = super::ConnectionPool::new(2, 2, true, &cfg).unwrap();
             assert_eq!(pool.init(), true);
             assert_eq!(pool.idle_conns_count(), 2);
 
@@ -471,13 +702,18 @@ pub mod tests {
             let c2 = pool.acquire().unwrap();
             info!("c2: {}", pool.idle_conns_count());
             assert_eq!(pool.idle_conns_count(), 0);
+            // pool is full, c3 is a temporary connection
             let c3 = pool.acquire().unwrap();
+            assert_eq!(pool.stats().pooled_inuse, 2);
+            assert_eq!(pool.stats().temporary_inuse, 1);
             pool.release(c1);
-            assert_eq!(pool.idle_conns_count(), 0);
+            assert_eq!(pool.idle_conns_count(), 1);
             pool.release(c2);
-            assert_eq!(pool.idle_conns_count(), 0);
+            assert_eq!(pool.idle_conns_count(), 2);
             pool.release(c3);
-            assert_eq!(pool.idle_conns_count(), 1);
+            assert_eq!(pool.idle_conns_count(), 2);
+            assert_eq!(pool.stats().pooled_inuse, 0);
+            assert_eq!(pool.stats().temporary_inuse, 0);
 
             pool.release_all();
             assert_eq!(pool.idle_conns_count(), 0);
@@ -501,7 +737,7 @@ pub mod tests {
 
             sleep(Duration::from_millis(1000));
 
-            let pool = super::ConnectionPool::new(2, 10, true, &cfg);
+            let pool = super::ConnectionPool::new(2, 10, true, &cfg).unwrap();
             assert_eq!(pool.init(), true);
             let pool_shared = Arc::new(pool);
             for _ in 0u32..10 {
@@ -516,7 +752,10 @@ pub mod tests {
                 });
             }
             sleep(Duration::from_millis(2000));
-            assert_eq!(pool_shared.idle_conns_count(), 1);
+            let stats = pool_shared.stats();
+            assert!(stats.idle >= 3 && stats.idle <= 10);
+            assert_eq!(stats.pooled_inuse, 0);
+            assert_eq!(stats.temporary_inuse, 0);
             pool_shared.release_all();
             assert_eq!(pool_shared.idle_conns_count(), 0);
             tx.send(0);
@@ -537,7 +776,7 @@ pub mod tests {
         let (tx, rx): (Sender<isize>, Receiver<isize>) = channel();
         thread::spawn(move || { listen_ip4_localhost(listen_port, rx); });
         sleep(Duration::from_millis(1000));
-        let pool = super::ConnectionPool::new(2, 3, true, &cfg);
+        let pool = super::ConnectionPool::new(2, 3, true, &cfg).unwrap();
         assert_eq!(pool.init(), true);
         let pool_shared = Arc::new(pool);
         for _ in 0u32..2 {
@@ -555,7 +794,7 @@ pub mod tests {
             "test_acquire_release_multithread_2 out of for loop :{}",
             pool_shared.idle_conns_count()
         );
-        assert_eq!(pool_shared.idle_conns_count(), 1);
+        assert_eq!(pool_shared.idle_conns_count(), 2);
         pool_shared.release_all();
         assert_eq!(pool_shared.idle_conns_count(), 0);
         tx.send(0);
@@ -564,44 +803,744 @@ pub mod tests {
     }
 
     #[test]
-    #[cfg(feature = "ssl")]
-    fn test_init_ssl() {
-        info!("test_init_ssl started---------");
+    fn test_leak_detection() {
+        info!("test_leak_detection started---------");
+        let (mut cfg, tx) = start_listener();
+        cfg.leak_detection_threshold = Some(Duration::from_millis(100));
+        cfg.leak_detection_backtrace = Some(true);
+
+        let pool = Arc::new(super::ConnectionPool::new(1, 5, true, &cfg).unwrap());
+        assert_eq!(pool.init(), true);
+        assert_eq!(pool.checked_out().len(), 0);
+
+        let p1 = pool.clone();
+        let c1 = thread::Builder::new()
+            .name("leaky".to_string())
+            .spawn(move || p1.acquire().unwrap())
+            .unwrap()
+            .join()
+            .unwrap();
+        let c2 = pool.acquire().unwrap();
+        let held = pool.checked_out();
+        assert_eq!(held.len(), 2);
+        let leaky = held.iter().find(|i| &i.id == c1.id()).unwrap();
+        assert_eq!(leaky.thread, "leaky");
+        assert!(leaky.backtrace.is_some());
+
+        sleep(Duration::from_millis(200));
+        assert_eq!(pool.check_leaks(), 2);
+        // already reported
+        assert_eq!(pool.check_leaks(), 0);
+
+        pool.release(c1);
+        assert_eq!(pool.checked_out().len(), 1);
+        super::ConnectionPool::drop(&pool, c2);
+        assert_eq!(pool.checked_out().len(), 0);
+        pool.release_all();
+        tx.send(0);
+        info!("test_leak_detection ended---------");
+    }
+
+    #[test]
+    fn test_leak_detection_disabled() {
+        let cfg: config::Config = Default::default();
+        let pool = super::ConnectionPool::new(0, 5, false, &cfg).unwrap();
+        assert_eq!(pool.checked_out().len(), 0);
+        assert_eq!(pool.check_leaks(), 0);
+    }
+
+    #[cfg(test)]
+    fn start_listener() -> (config::Config, Sender<isize>) {
+        // let the OS pick the port
+        let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg: config::Config = Default::default();
+        cfg.port = Some(acceptor.local_addr().unwrap().port());
+        cfg.server = Some("127.0.0.1".to_string());
+        let (tx, rx): (Sender<isize>, Receiver<isize>) = channel();
+        thread::spawn(move || { serve(acceptor, rx); });
+        (cfg, tx)
+    }
+
+    /// Echo server on a Unix domain socket in the temp dir. Returns the socket path
+    #[cfg(test)]
+    fn start_unix_listener() -> PathBuf {
+        let path = env::temp_dir()
+            .join(format!("net-utils-{}-{}.sock", process::id(), next_test_port()));
+        let _ = fs::remove_file(&path);
+        let acceptor = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || for stream in acceptor.incoming() {
+            if let Ok(stream) = stream {
+                thread::spawn(move || handle_client(stream));
+            }
+        });
+        path
+    }
+
+    #[cfg(test)]
+    fn counting_probe(calls: Arc<AtomicUsize>, result: bool) -> config::ValidationProbe {
+        Arc::new(move |_: &mut conn::Connection| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            result
+        })
+    }
+
+    #[test]
+    fn test_validation_ping_probe() {
+        let (mut cfg, tx) = start_listener();
+        cfg.validation = Some(config::ValidationMode::OnBorrow);
+        cfg.validation_probe = Some(Arc::new(|c: &mut conn::Connection| {
+            if c.writer.write_all(b"PING\r\n").is_err() || c.writer.flush().is_err() {
+                return false;
+            }
+            let mut line = String::new();
+            match c.reader.read_line(&mut line) {
+                Ok(_) => line.trim_end() == "PING",
+                Err(_) => false,
+            }
+        }));
+        let pool = super::ConnectionPool::new(2, 5, true, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        // the first idle connection answers the PING so none are discarded
+        let c1 = pool.acquire().unwrap();
+        assert_eq!(pool.idle_conns_count(), 1);
+        pool.drop(c1);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_validation_on_borrow() {
+        let (mut cfg, tx) = start_listener();
+        let calls = Arc::new(AtomicUsize::new(0));
+        cfg.validation = Some(config::ValidationMode::OnBorrow);
+        cfg.validation_probe = Some(counting_probe(calls.clone(), false));
+        cfg.validation_retries = Some(1);
+        let pool = super::ConnectionPool::new(3, 5, true, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        assert_eq!(pool.idle_conns_count(), 3);
+        // two idle connections fail validation, then a new one is opened
+        let c1 = pool.acquire().unwrap();
+        assert!(c1.is_valid());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle_conns_count(), 1);
+        pool.drop(c1);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_validation_on_return() {
+        let (mut cfg, tx) = start_listener();
+        let calls = Arc::new(AtomicUsize::new(0));
+        cfg.validation = Some(config::ValidationMode::OnReturn);
+        cfg.validation_probe = Some(counting_probe(calls.clone(), false));
+        let pool = super::ConnectionPool::new(2, 5, true, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        let c1 = pool.acquire().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        pool.release(c1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_conns_count(), 1);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_validation_while_idle() {
+        let (mut cfg, tx) = start_listener();
+        let calls = Arc::new(AtomicUsize::new(0));
+        cfg.validation = Some(config::ValidationMode::WhileIdle(Duration::from_millis(100)));
+        cfg.validation_probe = Some(counting_probe(calls.clone(), false));
+        let pool = super::ConnectionPool::new(2, 5, true, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        assert_eq!(pool.validate_idle(), 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        sleep(Duration::from_millis(200));
+        assert_eq!(pool.validate_idle(), 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle_conns_count(), 0);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_max_idle() {
+        let (mut cfg, tx) = start_listener();
+        cfg.max_idle = Some(1);
+        let pool = super::ConnectionPool::new(0, 3, false, &cfg).unwrap();
+        let c1 = pool.acquire().unwrap();
+        let c2 = pool.acquire().unwrap();
+        let c3 = pool.acquire().unwrap();
+        assert!(pool.acquire().is_err());
+        pool.release(c1);
+        pool.release(c2);
+        pool.release(c3);
+        assert_eq!(
+            pool.stats(),
+            super::PoolStats {
+                idle: 1,
+                pooled_inuse: 0,
+                temporary_inuse: 0,
+                max_conns: 3,
+                max_idle: 1,
+                idle_strategy: config::IdleStrategy::Fifo,
+            }
+        );
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[cfg(test)]
+    fn idle_strategy_pick(strategy: config::IdleStrategy) -> bool {
+        let (mut cfg, tx) = start_listener();
+        cfg.idle_strategy = Some(strategy);
+        let pool = super::ConnectionPool::new(0, 3, false, &cfg).unwrap();
+        let c1 = pool.acquire().unwrap();
+        let c2 = pool.acquire().unwrap();
+        let first = c1.id().clone();
+        pool.release(c1);
+        pool.release(c2);
+        let c = pool.acquire().unwrap();
+        let picked_first = c.id() == &first;
+        assert_eq!(pool.stats().idle_strategy, strategy);
+        pool.release(c);
+        pool.release_all();
+        tx.send(0);
+        picked_first
+    }
+
+    #[test]
+    fn test_idle_strategy() {
+        assert_eq!(idle_strategy_pick(config::IdleStrategy::Fifo), true);
+        assert_eq!(idle_strategy_pick(config::IdleStrategy::Lifo), false);
+    }
+
+    #[test]
+    fn test_trim_idle() {
+        let (mut cfg, tx) = start_listener();
+        cfg.idle_strategy = Some(config::IdleStrategy::Lifo);
+        cfg.idle_timeout = Some(Duration::from_millis(200));
+        let pool = super::ConnectionPool::new(1, 3, false, &cfg).unwrap();
+        let c1 = pool.acquire().unwrap();
+        let c2 = pool.acquire().unwrap();
+        let c3 = pool.acquire().unwrap();
+        pool.release(c1);
+        pool.release(c2);
+        pool.release(c3);
+        assert_eq!(pool.trim_idle(), 0);
+        // LIFO keeps reusing the hottest connection, the others go cold
+        for _ in 0..3 {
+            sleep(Duration::from_millis(100));
+            let c = pool.acquire().unwrap();
+            pool.release(c);
+        }
+        assert_eq!(pool.trim_idle(), 2);
+        assert_eq!(pool.idle_conns_count(), 1);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_refresh_addresses() {
+        let (cfg, tx) = start_listener();
+        let (cfg2, tx2) = start_listener();
+        let old = format!("127.0.0.1:{}", cfg.port.unwrap()).parse().unwrap();
+        let new = format!("127.0.0.1:{}", cfg2.port.unwrap()).parse().unwrap();
+        let resolver = Arc::new(TestResolver::new(vec![old]));
+        let mut cfg = cfg;
+        cfg.server = Some("service.test".to_string());
+        cfg.resolver = Some(resolver.clone());
+        cfg.resolve_interval = Some(Duration::from_millis(300));
+        let pool = super::ConnectionPool::new(0, 3, false, &cfg).unwrap();
+        let c1 = pool.acquire().unwrap();
+        let c2 = pool.acquire().unwrap();
+        assert_eq!(c1.remote_addr(), Some(old));
+        pool.release(c1);
+        pool.release(c2);
+        // connects between re-resolves use the cached answer
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 1);
+
+        // blue/green switch
+        *resolver.addrs.lock().unwrap() = vec![new];
+        let c = pool.acquire().unwrap();
+        assert_eq!(c.remote_addr(), Some(old));
+        pool.release(c);
+        sleep(Duration::from_millis(400));
+        let c = pool.acquire().unwrap();
+        assert_eq!(c.remote_addr(), Some(new));
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle_conns_count(), 0);
+        pool.release(c);
+        assert_eq!(pool.refresh_addresses().unwrap(), 0);
+        assert_eq!(pool.idle_conns_count(), 1);
+
+        // only one of the callers which find the re-resolve due does the lookup
+        let conns: Vec<conn::Connection> = (0..3).map(|_| pool.acquire().unwrap()).collect();
+        for c in conns {
+            pool.release(c);
+        }
+        let lookups = resolver.lookups.load(Ordering::SeqCst);
+        sleep(Duration::from_millis(400));
+        let pool = Arc::new(pool);
+        let ts: Vec<_> = (0..3)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let c = pool.acquire().unwrap();
+                    pool.release(c);
+                })
+            })
+            .collect();
+        for t in ts {
+            t.join().unwrap();
+        }
+        assert_eq!(resolver.lookups.load(Ordering::SeqCst), lookups + 1);
+        pool.release_all();
+        tx.send(0);
+        tx2.send(0);
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let path = start_unix_listener();
         let mut cfg: config::Config = Default::default();
-        cfg.port = Some(443);
-        cfg.server = Some("google.com".to_string());
+        cfg.server = Some(format!("unix://{}", path.display()));
+        cfg.read_timeout = Some(Duration::from_millis(200));
+        let pool = super::ConnectionPool::new(1, 2, false, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        let mut c = pool.acquire().unwrap();
+        assert!(c.is_valid());
+        assert_eq!(c.remote_addr(), None);
+        c.writer.write_all(b"a").unwrap();
+        c.writer.flush().unwrap();
+        let mut buf = [0; 1];
+        c.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"a");
+        // nothing more to read
+        let e = c.reader.read(&mut buf).err().unwrap();
+        assert!(e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut);
+        pool.release(c);
+        assert_eq!(pool.idle_conns_count(), 1);
+        assert_eq!(pool.refresh_addresses().unwrap(), 0);
+        pool.release_all();
+
+        // only the socket path set
+        let mut cfg: config::Config = Default::default();
+        cfg.server = None;
+        cfg.port = None;
+        cfg.unix_socket = Some(path.clone());
+        let pool = super::ConnectionPool::new(1, 1, false, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        assert_eq!(pool.idle_conns_count(), 1);
+        pool.release_all();
+        fs::remove_file(&path);
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn test_unix_socket_ssl() {
+        use crate::net::test_support::test_acceptor;
+
+        let path = env::temp_dir().join(format!("net-utils-{}-ssl.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let acceptor = test_acceptor();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_client(acceptor.accept(stream).unwrap());
+        });
+        let mut cfg: config::Config = Default::default();
+        cfg.unix_socket = Some(path.clone());
         cfg.use_ssl = Some(true);
         cfg.verify = Some(false);
         cfg.read_timeout = Some(Duration::from_millis(5_000));
+        let pool = super::ConnectionPool::new(0, 1, false, &cfg).unwrap();
+        let mut c = pool.acquire().unwrap();
+        assert!(c.is_valid());
+        match *c.reader.get_ref() {
+            conn::NetStream::SslUnixStream(_) => {}
+            _ => panic!("expected an SSL Unix domain socket"),
+        }
+        c.writer.write_all(b"ping").unwrap();
+        c.writer.flush().unwrap();
+        let mut buf = [0; 4];
+        c.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        pool.release(c);
+        pool.release_all();
+        fs::remove_file(&path);
+    }
+
+    /// SSL echo server, one thread per connection. Returns the config to reach it without
+    /// verifying its certificate
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    fn start_ssl_listener() -> config::Config {
+        use crate::net::test_support::test_acceptor;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some("127.0.0.1".to_string());
+        cfg.port = Some(listener.local_addr().unwrap().port());
+        cfg.use_ssl = Some(true);
+        cfg.verify = Some(false);
+        let acceptor = test_acceptor();
+        thread::spawn(move || for stream in listener.incoming() {
+            let acceptor = acceptor.clone();
+            thread::spawn(move || if let Ok(s) = acceptor.accept(stream.unwrap()) {
+                handle_client(s);
+            });
+        });
+        cfg
+    }
 
-        // cfg.server = Some("google.com".to_string());
-        let pool = super::ConnectionPool::new(2, 5, false, &cfg);
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn test_ssl_session_resumption() {
+        let cfg = start_ssl_listener();
+        let pool = super::ConnectionPool::new(0, 3, false, &cfg).unwrap();
+        let first = pool.acquire().unwrap();
+        assert!(!first.session_reused());
+        // the other connections resume the first one's session
+        let second = pool.acquire().unwrap();
+        assert!(second.session_reused());
+        let third = pool.acquire().unwrap();
+        assert!(third.session_reused());
+        pool.release(first);
+        pool.release(second);
+        pool.release(third);
+
+        // plain connections do not share the pool's cache
+        assert!(!conn::Connection::connect(&cfg).unwrap().session_reused());
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn test_tls_reload() {
+        use crate::net::test_support::{test_ca, test_cert};
+        use crate::net::tls::TlsIdentity;
+
+        let mut cfg = start_ssl_listener();
+        let ca = test_ca();
+        let (cert1, key1) = test_cert(&ca, &["client"]);
+        let (cert2, key2) = test_cert(&ca, &["client"]);
+        let cert_file = env::temp_dir().join(format!("net-utils-{}-client.pem", process::id()));
+        let key_file = env::temp_dir().join(format!("net-utils-{}-client.key", process::id()));
+        fs::write(&cert_file, cert1.to_pem().unwrap()).unwrap();
+        fs::write(&key_file, key1.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        cfg.certificate_file = Some(cert_file.clone());
+        cfg.private_key_file = Some(key_file.clone());
+        cfg.tls_reload_interval = Some(Duration::from_millis(0));
+        cfg.tls_recycle_on_reload = Some(true);
+        let pool = super::ConnectionPool::new(0, 2, false, &cfg).unwrap();
+        let connector = pool.tls_connector().unwrap().clone();
+        let c = pool.acquire().unwrap();
+        pool.release(c);
+        assert_eq!(pool.idle_conns_count(), 1);
+        assert_eq!(pool.reload_tls().unwrap(), false);
+
+        // half way through the rotation the old connector is kept
+        fs::write(&cert_file, cert2.to_pem().unwrap()).unwrap();
+        assert!(pool.reload_tls().is_err());
+        let c = pool.acquire().unwrap();
+        assert_eq!(connector.generation(), 0);
+        assert_eq!(pool.idle_conns_count(), 0);
+        pool.release(c);
+
+        fs::write(&key_file, key2.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let c = pool.acquire().unwrap();
+        assert_eq!(connector.generation(), 1);
+        // the idle connection was recycled, this one is new
+        assert_eq!(pool.idle_conns_count(), 0);
+        assert_eq!(pool.stats().pooled_inuse, 1);
+
+        // pushed material, the checked out connection is closed when it is released
+        connector.set_identity(TlsIdentity::Pem {
+            certs: cert1.to_pem().unwrap(),
+            key: key1.private_key_to_pem_pkcs8().unwrap(),
+            passphrase: None,
+        }).unwrap();
+        assert_eq!(connector.generation(), 2);
+        let d = pool.acquire().unwrap();
+        pool.release(c);
+        assert_eq!(pool.idle_conns_count(), 0);
+        pool.release(d);
+        assert_eq!(pool.idle_conns_count(), 1);
+        fs::remove_file(&cert_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn test_tls_connector_built_once() {
+        use crate::net::test_support::test_ca;
+
+        let mut cfg = start_ssl_listener();
+        let ca_file = env::temp_dir().join(format!("net-utils-{}-pool-ca.pem", process::id()));
+        cfg.ca_file = Some(ca_file.clone());
+        assert!(super::ConnectionPool::new(0, 2, false, &cfg).is_err());
+
+        fs::write(&ca_file, test_ca().0.to_pem().unwrap()).unwrap();
+        let pool = super::ConnectionPool::new(0, 2, false, &cfg).unwrap();
+        // the CA file is not read again
+        fs::remove_file(&ca_file).unwrap();
+        let c = pool.acquire().unwrap();
+        assert!(conn::Connection::connect(&cfg).is_err());
+
+        // pools sharing a connector resume each other's sessions
+        cfg.tls_connector = pool.tls_connector().cloned();
+        let other = super::ConnectionPool::new(0, 2, false, &cfg).unwrap();
+        let d = other.acquire().unwrap();
+        assert!(d.session_reused());
+        assert!(conn::Connection::connect(&cfg).unwrap().session_reused());
+        #[cfg(feature = "ssl")]
+        assert_eq!(pool.tls_connector().unwrap().session_cache().len(), 1);
+        pool.release(c);
+        other.release(d);
+
+        cfg.tls_connector = None;
+        cfg.tls_min_version = Some(crate::net::tls::TlsVersion::Tls13);
+        cfg.tls_max_version = Some(crate::net::tls::TlsVersion::Tls12);
+        assert!(super::ConnectionPool::new(0, 2, false, &cfg).is_err());
+    }
+
+    /// Echo server which first reads a PROXY v1 header and sends it on the returned channel
+    #[cfg(test)]
+    fn start_proxy_protocol_listener() -> (config::Config, Receiver<String>) {
+        let acceptor = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut cfg: config::Config = Default::default();
+        cfg.port = Some(acceptor.local_addr().unwrap().port());
+        cfg.server = Some("127.0.0.1".to_string());
+        let (tx, rx) = channel();
+        thread::spawn(move || for stream in acceptor.incoming() {
+            let stream = stream.unwrap();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                tx.send(header).unwrap();
+                handle_client(stream);
+            });
+        });
+        (cfg, rx)
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        use crate::net::proxy_protocol::{ProxyHeader, ProxyProtocolVersion};
+
+        let (mut cfg, rx) = start_proxy_protocol_listener();
+        cfg.proxy_protocol = Some(ProxyProtocolVersion::V1);
+        let port = cfg.port.unwrap();
+        let pool = super::ConnectionPool::new(0, 1, false, &cfg).unwrap();
+
+        // the connection's own addresses by default
+        let c = pool.acquire().unwrap();
+        let local_port = match *c.writer.get_ref() {
+            conn::NetStream::UnsecuredTcpStream(ref tcp) => tcp.local_addr().unwrap().port(),
+            _ => panic!("expected a TCP stream"),
+        };
+        assert_eq!(
+            rx.recv().unwrap(),
+            format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n", local_port, port)
+        );
+        assert_eq!(c.proxy_header(), None);
+        pool.release(c);
+
+        // per acquire addresses, the idle connection is replaced
+        let header = ProxyHeader::new(
+            "192.0.2.10:40000".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        );
+        let mut c = pool.acquire_with_proxy_header(&header).unwrap();
+        assert_eq!(rx.recv().unwrap(), "PROXY TCP4 192.0.2.10 198.51.100.1 40000 443\r\n");
+        assert_eq!(c.proxy_header(), Some(&header));
+        c.writer.write_all(b"a").unwrap();
+        c.writer.flush().unwrap();
+        let mut buf = [0; 1];
+        c.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"a");
+        let id = c.id().clone();
+        pool.release(c);
+
+        // same header, same connection
+        let c = pool.acquire_with_proxy_header(&header).unwrap();
+        assert_eq!(c.id(), &id);
+        assert!(rx.try_recv().is_err());
+        pool.release(c);
+        pool.release_all();
+    }
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[test]
+    fn test_proxy_protocol_before_ssl() {
+        use crate::net::proxy_protocol::{ProxyHeader, ProxyProtocolVersion};
+        use crate::net::test_support::test_acceptor;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = test_acceptor();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // v2 header with IPv4 addresses and no TLVs
+            let mut header = [0; 28];
+            stream.read_exact(&mut header).unwrap();
+            tx.send(header.to_vec()).unwrap();
+            handle_client(acceptor.accept(stream).unwrap());
+        });
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some("127.0.0.1".to_string());
+        cfg.port = Some(port);
+        cfg.use_ssl = Some(true);
+        cfg.verify = Some(false);
+        cfg.proxy_protocol = Some(ProxyProtocolVersion::V2);
+        let header = ProxyHeader::new(
+            "192.0.2.10:40000".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        );
+        let mut c = conn::Connection::connect_with_proxy_header(&cfg, header.clone()).unwrap();
+        assert_eq!(rx.recv().unwrap(), header.encode(ProxyProtocolVersion::V2).unwrap());
+        c.writer.write_all(b"ping").unwrap();
+        c.writer.flush().unwrap();
+        let mut buf = [0; 4];
+        c.reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[cfg(test)]
+    fn priority_order(aging: Duration) -> Vec<super::Priority> {
+        let (mut cfg, tx) = start_listener();
+        cfg.priority_aging = Some(aging);
+        let pool = Arc::new(super::ConnectionPool::new(0, 1, false, &cfg).unwrap());
+        let c1 = pool.acquire().unwrap();
+        let (order_tx, order_rx) = channel();
+        let mut ts = Vec::new();
+        for priority in vec![super::Priority::Low, super::Priority::High] {
+            let pool = pool.clone();
+            let order_tx = order_tx.clone();
+            ts.push(thread::spawn(move || {
+                let c = pool.acquire_with_priority(priority, Duration::from_millis(5_000)).unwrap();
+                order_tx.send(priority).unwrap();
+                sleep(Duration::from_millis(50));
+                pool.release(c);
+            }));
+            sleep(Duration::from_millis(300));
+        }
+        pool.release(c1);
+        for t in ts {
+            t.join().unwrap();
+        }
+        tx.send(0);
+        order_rx.try_iter().collect()
+    }
+
+    #[test]
+    fn test_acquire_with_priority() {
+        use super::Priority;
+        assert_eq!(
+            priority_order(Duration::from_millis(10_000)),
+            vec![Priority::High, Priority::Low]
+        );
+        // the low priority waiter has aged past the high priority one
+        assert_eq!(
+            priority_order(Duration::from_millis(100)),
+            vec![Priority::Low, Priority::High]
+        );
+    }
+
+    #[test]
+    fn test_acquire_with_priority_reserved() {
+        use std::io::ErrorKind;
+        use super::Priority;
+        let (mut cfg, tx) = start_listener();
+        cfg.reserved_conns = Some(1);
+        let pool = super::ConnectionPool::new(0, 2, false, &cfg).unwrap();
+        let c1 = pool.acquire().unwrap();
+        assert!(pool.acquire().is_err());
+        let e = pool.acquire_with_priority(Priority::Normal, Duration::from_millis(100));
+        assert_eq!(e.err().unwrap().kind(), ErrorKind::TimedOut);
+        let c2 = pool.acquire_with_priority(Priority::High, Duration::from_millis(100)).unwrap();
+        let e = pool.acquire_with_priority(Priority::High, Duration::from_millis(100));
+        assert_eq!(e.err().unwrap().kind(), ErrorKind::TimedOut);
+        pool.release(c1);
+        pool.release(c2);
+        assert_eq!(pool.stats().pooled_inuse, 0);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_release_after_release_all() {
+        let (cfg, tx) = start_listener();
+        let pool = super::ConnectionPool::new(1, 2, true, &cfg).unwrap();
         assert_eq!(pool.init(), true);
-        let mut conn = pool.acquire().unwrap();
-        assert_eq!(conn.is_valid(), true);
-        warn!("test_example error---------");
-        println!("test_init_ssl: sending GET request");
-        conn.writer.write("GET /index.html\r\n".as_bytes());
-        println!("test_init_ssl: flushing buffer");
-        conn.writer.flush();
-        let mut buffer = String::new();
-        //let mut buffer_byte = [0; 10];
-
-        println!("test_init_ssl: reading a line");
-        let r = conn.reader.read_to_string(&mut buffer);
-        println!("test_init_ssl: printing read buffer");
-        match r {
-            Ok(v) => {
-                if v > 0 {
-                    println!("test_init_ssl:Received {}", buffer);
+        let c1 = pool.acquire().unwrap();
+        pool.release_all();
+        assert_eq!(pool.stats().pooled_inuse, 1);
+        // checked out before release_all so it is not pooled again
+        pool.release(c1);
+        assert_eq!(pool.idle_conns_count(), 0);
+        assert_eq!(pool.stats().pooled_inuse, 0);
+        tx.send(0);
+    }
+
+    #[test]
+    fn test_counters_stress() {
+        let (cfg, tx) = start_listener();
+        let max_conns = 4;
+        let pool = Arc::new(super::ConnectionPool::new(2, max_conns, true, &cfg).unwrap());
+        assert_eq!(pool.init(), true);
+        let mut ts = Vec::new();
+        for t in 0..8 {
+            let pool = pool.clone();
+            ts.push(thread::spawn(move || for i in 0..50 {
+                let c1 = pool.acquire().unwrap();
+                let c2 = pool.acquire().unwrap();
+                let stats = pool.stats();
+                assert!(stats.idle + stats.pooled_inuse <= max_conns);
+                assert!(stats.idle <= stats.max_idle);
+                if (t + i) % 3 == 0 {
+                    super::ConnectionPool::drop(&pool, c1);
                 } else {
-                    println!("test_init_ssl:Received {}", v);
+                    pool.release(c1);
                 }
-            }
-            Err(e) => println!("test_init_ssl:error : {:?}", e),
-
+                pool.release(c2);
+            }));
         }
-        println!("test_init_ssl: releasing connection");
+        for t in ts {
+            t.join().unwrap();
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.pooled_inuse, 0);
+        assert_eq!(stats.temporary_inuse, 0);
+        assert!(stats.idle <= max_conns);
+        pool.release_all();
+        tx.send(0);
+    }
+
+    #[test]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    fn test_init_ssl() {
+        use crate::net::test_support::{echo_server, TestPki};
+
+        info!("test_init_ssl started---------");
+        // local echo server requiring a client certificate
+        let pki = TestPki::new();
+        let port = echo_server(&pki.server, Some(&pki.ca.0));
+        let cfg = pki.config(port, "init-ssl");
+
+        let pool = super::ConnectionPool::new(2, 5, false, &cfg).unwrap();
+        assert_eq!(pool.init(), true);
+        assert_eq!(pool.idle_conns_count(), 2);
+        let mut conn = pool.acquire().unwrap();
+        assert_eq!(conn.is_valid(), true);
+        assert!(conn.tls_info().is_some());
+        conn.writer.write_all(b"GET /index.html\r\n").unwrap();
+        conn.writer.flush().unwrap();
+        let mut line = String::new();
+        conn.reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "GET /index.html\r\n");
         pool.release(conn);
 
         pool.release_all();
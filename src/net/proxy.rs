@@ -0,0 +1,709 @@
+//! Tunneling connections through a SOCKS5 or HTTP CONNECT proxy.  The tunnel is set up right after
+//! the TCP connect to the proxy, SSL (if enabled) then runs end to end through it.
+//!
+//! Failures of the proxy itself (refused credentials, policy) and failures of the proxy to reach the
+//! target are reported as `io::Error`s carrying a `ProxyError`, see `proxy_error`.
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+
+use crate::net::config;
+use crate::net::socket;
+
+/// Longest HTTP CONNECT response header accepted from the proxy
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+/// Username and password for the proxy
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyAuth {
+    /// Username
+    pub username: String,
+    /// Password
+    pub password: String,
+}
+
+/// Proxy to tunnel connections through
+#[derive(Clone, Debug, PartialEq)]
+pub enum Proxy {
+    /// SOCKS5 (RFC 1928) with optional username/password authentication (RFC 1929)
+    Socks5 {
+        /// Proxy host
+        server: String,
+        /// Proxy port
+        port: u16,
+        /// Credentials, if the proxy requires them
+        auth: Option<ProxyAuth>,
+        /// Let the proxy resolve the target host name instead of resolving it locally
+        remote_dns: bool,
+    },
+    /// HTTP CONNECT with optional basic authentication
+    HttpConnect {
+        /// Proxy host
+        server: String,
+        /// Proxy port
+        port: u16,
+        /// Credentials, if the proxy requires them
+        auth: Option<ProxyAuth>,
+    },
+}
+
+impl Proxy {
+    /// Proxy host and port
+    fn endpoint(&self) -> (&str, u16) {
+        match *self {
+            Proxy::Socks5 { ref server, port, .. } |
+            Proxy::HttpConnect { ref server, port, .. } => (server, port),
+        }
+    }
+}
+
+/// Why a tunnel through the proxy could not be opened
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProxyError {
+    /// The proxy did not accept our credentials, or wants credentials we do not have
+    AuthFailed(String),
+    /// The proxy refused to open the tunnel, e.g. the target is not allowed by its rules
+    Refused(String),
+    /// The proxy could not connect to the target
+    TargetFailed(String),
+    /// The proxy sent something we do not understand
+    Protocol(String),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyError::AuthFailed(ref msg) => write!(f, "Proxy authentication failed: {}", msg),
+            ProxyError::Refused(ref msg) => write!(f, "Proxy refused the connection: {}", msg),
+            ProxyError::TargetFailed(ref msg) => {
+                write!(f, "Proxy could not connect to the target: {}", msg)
+            }
+            ProxyError::Protocol(ref msg) => write!(f, "Proxy protocol error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for ProxyError {}
+
+impl From<ProxyError> for Error {
+    fn from(e: ProxyError) -> Error {
+        let kind = match e {
+            ProxyError::AuthFailed(_) | ProxyError::Refused(_) => ErrorKind::PermissionDenied,
+            ProxyError::TargetFailed(_) => ErrorKind::ConnectionRefused,
+            ProxyError::Protocol(_) => ErrorKind::InvalidData,
+        };
+        Error::new(kind, e)
+    }
+}
+
+/// The `ProxyError` carried by `e`, if the error came from the proxy handshake
+pub fn proxy_error(e: &Error) -> Option<&ProxyError> {
+    e.get_ref().and_then(|e| e.downcast_ref::<ProxyError>())
+}
+
+/// Connect to `Config::proxy` and open a tunnel to the server and port in the config.
+/// Returns the tunneled stream and the address of the proxy
+pub fn connect(config: &config::Config, proxy: &Proxy) -> Result<(TcpStream, SocketAddr)> {
+    let (proxy_host, proxy_port) = proxy.endpoint();
+    let mut proxy_config = config.clone();
+    proxy_config.server = Some(proxy_host.to_string());
+    proxy_config.port = Some(proxy_port);
+    let (mut stream, proxy_addr) = socket::connect(&proxy_config).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("Failed to connect to proxy {}:{} ({})", proxy_host, proxy_port, e),
+        )
+    })?;
+
+    // the handshake is bounded by the connect timeout
+    stream.set_read_timeout(config.connect_timeout.or(config.read_timeout))?;
+    stream.set_write_timeout(config.connect_timeout.or(config.write_timeout))?;
+    let host = config.server.clone().unwrap();
+    let port = config.port.unwrap();
+    info!(
+        "Opening tunnel to {}:{} through proxy {}",
+        host,
+        port,
+        proxy_addr
+    );
+    match *proxy {
+        Proxy::Socks5 { ref auth, remote_dns, .. } => {
+            let target = if remote_dns || host.parse::<IpAddr>().is_ok() {
+                Target::Name(&host, port)
+            } else {
+                // the proxy gets a single address, the first one in Happy Eyeballs order
+                match socket::resolve(config)?.into_iter().next() {
+                    Some(addr) => Target::Addr(addr),
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("{}:{} did not resolve to any address", host, port),
+                        ))
+                    }
+                }
+            };
+            socks5_handshake(&mut stream, auth.as_ref(), target)?;
+        }
+        Proxy::HttpConnect { ref auth, .. } => {
+            http_connect_handshake(&mut stream, auth.as_ref(), &host, port)?;
+        }
+    }
+    stream.set_read_timeout(config.read_timeout)?;
+    stream.set_write_timeout(config.write_timeout)?;
+    Ok((stream, proxy_addr))
+}
+
+/// Target of a SOCKS5 CONNECT
+enum Target<'a> {
+    /// Host name or IP literal, resolved by the proxy
+    Name(&'a str, u16),
+    /// Resolved address
+    Addr(SocketAddr),
+}
+
+/// SOCKS5 greeting, optional username/password authentication and CONNECT
+fn socks5_handshake<S: Read + Write>(
+    stream: &mut S,
+    auth: Option<&ProxyAuth>,
+    target: Target,
+) -> Result<()> {
+    // greeting: version 5, methods
+    if auth.is_some() {
+        stream.write_all(&[5, 2, 0x00, 0x02])?;
+    } else {
+        stream.write_all(&[5, 1, 0x00])?;
+    }
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 5 {
+        return Err(ProxyError::Protocol(format!("SOCKS version {}", reply[0])).into());
+    }
+    match (reply[1], auth) {
+        (0x00, _) => {}
+        (0x02, Some(auth)) => {
+            if auth.username.len() > 255 || auth.password.len() > 255 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "SOCKS5 username and password are limited to 255 bytes",
+                ));
+            }
+            let mut request = vec![1, auth.username.len() as u8];
+            request.extend_from_slice(auth.username.as_bytes());
+            request.push(auth.password.len() as u8);
+            request.extend_from_slice(auth.password.as_bytes());
+            stream.write_all(&request)?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0 {
+                return Err(ProxyError::AuthFailed(
+                    format!("username/password rejected (status {})", status[1]),
+                ).into());
+            }
+        }
+        (0xff, _) | (0x02, None) => {
+            return Err(ProxyError::AuthFailed(
+                "no acceptable authentication method".to_string(),
+            ).into());
+        }
+        (method, _) => {
+            return Err(ProxyError::Protocol(
+                format!("unexpected authentication method {}", method),
+            ).into());
+        }
+    }
+
+    // CONNECT
+    let mut request = vec![5, 1, 0];
+    let port = match target {
+        Target::Addr(addr) => {
+            push_socks5_ip(&mut request, addr.ip());
+            addr.port()
+        }
+        Target::Name(host, port) => {
+            match host.parse::<IpAddr>() {
+                Ok(ip) => push_socks5_ip(&mut request, ip),
+                Err(_) => {
+                    if host.len() > 255 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "SOCKS5 host names are limited to 255 bytes",
+                        ));
+                    }
+                    request.push(3);
+                    request.push(host.len() as u8);
+                    request.extend_from_slice(host.as_bytes());
+                }
+            }
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 5 {
+        return Err(ProxyError::Protocol(format!("SOCKS version {}", reply[0])).into());
+    }
+    match reply[1] {
+        0x00 => {}
+        0x01 => return Err(ProxyError::Refused("general SOCKS server failure".to_string()).into()),
+        0x02 => return Err(ProxyError::Refused("connection not allowed by ruleset".to_string()).into()),
+        0x03 => return Err(ProxyError::TargetFailed("network unreachable".to_string()).into()),
+        0x04 => return Err(ProxyError::TargetFailed("host unreachable".to_string()).into()),
+        0x05 => return Err(ProxyError::TargetFailed("connection refused".to_string()).into()),
+        0x06 => return Err(ProxyError::TargetFailed("TTL expired".to_string()).into()),
+        0x07 => return Err(ProxyError::Refused("command not supported".to_string()).into()),
+        0x08 => return Err(ProxyError::Refused("address type not supported".to_string()).into()),
+        code => return Err(ProxyError::Protocol(format!("SOCKS reply {}", code)).into()),
+    }
+    // skip the bound address and port
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => return Err(ProxyError::Protocol(format!("SOCKS address type {}", atyp)).into()),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+/// Append a SOCKS5 IPv4 or IPv6 address
+fn push_socks5_ip(request: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+/// Send CONNECT and read the response header.  The body, if any, is not read: a successful
+/// response has none
+fn http_connect_handshake<S: Read + Write>(
+    stream: &mut S,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(auth) = auth {
+        let credentials = format!("{}:{}", auth.username, auth.password);
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(credentials.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    // read byte by byte so nothing past the header (e.g. the SSL handshake) is consumed
+    let mut header = Vec::new();
+    let mut byte = [0; 1];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HTTP_RESPONSE {
+            return Err(ProxyError::Protocol("response header too long".to_string()).into());
+        }
+        if stream.read(&mut byte)? == 0 {
+            return Err(ProxyError::Protocol(
+                "connection closed before the end of the response".to_string(),
+            ).into());
+        }
+        header.push(byte[0]);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let status_line = header.lines().next().unwrap_or("");
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok());
+    match status {
+        Some(200..=299) => Ok(()),
+        Some(407) => Err(ProxyError::AuthFailed(status_line.to_string()).into()),
+        Some(502) | Some(503) | Some(504) => {
+            Err(ProxyError::TargetFailed(status_line.to_string()).into())
+        }
+        Some(_) => Err(ProxyError::Refused(status_line.to_string()).into()),
+        None => Err(ProxyError::Protocol(format!("bad status line '{}'", status_line)).into()),
+    }
+}
+
+/// Standard base64 with padding, for the basic auth header
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    use super::{base64, proxy_error, Proxy, ProxyAuth, ProxyError};
+    use crate::net::config;
+    use crate::net::conn::Connection;
+
+    /// Echo server on an OS assigned port
+    fn start_echo() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                let _ = ::std::io::copy(&mut reader, &mut stream);
+            });
+        });
+        port
+    }
+
+    /// Copy bytes both ways between the client and the target
+    fn relay(client: TcpStream, target: TcpStream) {
+        let mut client_reader = client.try_clone().unwrap();
+        let mut target_writer = target.try_clone().unwrap();
+        thread::spawn(move || { let _ = ::std::io::copy(&mut client_reader, &mut target_writer); });
+        let (mut target_reader, mut client_writer) = (target, client);
+        let _ = ::std::io::copy(&mut target_reader, &mut client_writer);
+    }
+
+    /// Single connection SOCKS5 proxy which requires `auth` if set. Sends the requested target
+    /// ("host:port") on the returned channel
+    fn start_socks5(auth: Option<(&'static str, &'static str)>) -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut head = [0; 2];
+            s.read_exact(&mut head).unwrap();
+            let mut methods = vec![0; head[1] as usize];
+            s.read_exact(&mut methods).unwrap();
+            match auth {
+                Some((user, pass)) => {
+                    if !methods.contains(&2) {
+                        s.write_all(&[5, 0xff]).unwrap();
+                        return;
+                    }
+                    s.write_all(&[5, 2]).unwrap();
+                    let mut b = [0; 2];
+                    s.read_exact(&mut b).unwrap();
+                    let mut u = vec![0; b[1] as usize];
+                    s.read_exact(&mut u).unwrap();
+                    let mut l = [0; 1];
+                    s.read_exact(&mut l).unwrap();
+                    let mut p = vec![0; l[0] as usize];
+                    s.read_exact(&mut p).unwrap();
+                    if u != user.as_bytes() || p != pass.as_bytes() {
+                        s.write_all(&[1, 1]).unwrap();
+                        return;
+                    }
+                    s.write_all(&[1, 0]).unwrap();
+                }
+                None => s.write_all(&[5, 0]).unwrap(),
+            }
+            let mut req = [0; 4];
+            s.read_exact(&mut req).unwrap();
+            let host = match req[3] {
+                1 => {
+                    let mut ip = [0; 4];
+                    s.read_exact(&mut ip).unwrap();
+                    format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
+                }
+                3 => {
+                    let mut l = [0; 1];
+                    s.read_exact(&mut l).unwrap();
+                    let mut name = vec![0; l[0] as usize];
+                    s.read_exact(&mut name).unwrap();
+                    String::from_utf8(name).unwrap()
+                }
+                _ => panic!("unexpected address type"),
+            };
+            let mut port = [0; 2];
+            s.read_exact(&mut port).unwrap();
+            let target = format!("{}:{}", host, u16::from_be_bytes(port));
+            tx.send(target.clone()).unwrap();
+            let addr = target.to_socket_addrs().unwrap().find(|a| a.is_ipv4()).unwrap();
+            match TcpStream::connect(addr) {
+                Ok(t) => {
+                    s.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+                    relay(s, t);
+                }
+                Err(_) => s.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap(),
+            }
+        });
+        (port, rx)
+    }
+
+    /// Single connection HTTP CONNECT proxy which requires the basic `auth` header if set
+    fn start_http_proxy(auth: Option<&'static str>) -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(s.try_clone().unwrap());
+            let mut request = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                request.push(line.trim_end().to_string());
+            }
+            tx.send(request.join("\n")).unwrap();
+            let target = request[0].split_whitespace().nth(1).unwrap().to_string();
+            if let Some(auth) = auth {
+                let expected = format!("Proxy-Authorization: Basic {}", auth);
+                if !request.contains(&expected) {
+                    s.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap();
+                    return;
+                }
+            }
+            if target.starts_with("forbidden") {
+                s.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+                return;
+            }
+            match TcpStream::connect(&*target) {
+                Ok(t) => {
+                    s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+                    relay(s, t);
+                }
+                Err(_) => s.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").unwrap(),
+            }
+        });
+        (port, rx)
+    }
+
+    /// Config for the echo server on `port` through `proxy`
+    fn proxied(server: &str, port: u16, proxy: Proxy) -> config::Config {
+        let mut cfg: config::Config = Default::default();
+        cfg.server = Some(server.to_string());
+        cfg.port = Some(port);
+        cfg.proxy = Some(proxy);
+        cfg
+    }
+
+    fn auth(username: &str, password: &str) -> Option<ProxyAuth> {
+        Some(ProxyAuth {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    /// Send a line through the connection and read it back
+    fn ping(conn: &mut Connection) {
+        conn.writer.write_all(b"ping\n").unwrap();
+        conn.writer.flush().unwrap();
+        let mut line = String::new();
+        conn.reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ping\n");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"Aladdin:open sesame"), "QWxhZGRpbjpvcGVuIHNlc2FtZQ==");
+    }
+
+    #[test]
+    fn test_socks5_auth() {
+        let echo = start_echo();
+        let (proxy_port, rx) = start_socks5(Some(("user", "secret")));
+        let cfg = proxied("127.0.0.1", echo, Proxy::Socks5 {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: auth("user", "secret"),
+            remote_dns: false,
+        });
+        let mut conn = Connection::connect(&cfg).unwrap();
+        assert_eq!(rx.recv().unwrap(), format!("127.0.0.1:{}", echo));
+        assert_eq!(conn.remote_addr().unwrap().port(), proxy_port);
+        ping(&mut conn);
+    }
+
+    #[test]
+    fn test_socks5_remote_dns() {
+        let echo = start_echo();
+        let (proxy_port, rx) = start_socks5(None);
+        let cfg = proxied("localhost", echo, Proxy::Socks5 {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: None,
+            remote_dns: true,
+        });
+        let mut conn = Connection::connect(&cfg).unwrap();
+        // the host name is passed on unresolved
+        assert_eq!(rx.recv().unwrap(), format!("localhost:{}", echo));
+        ping(&mut conn);
+    }
+
+    #[test]
+    fn test_pool_refresh_through_proxy() {
+        use std::thread::sleep;
+        use std::time::Duration;
+
+        use crate::net::poolmgr::ConnectionPool;
+
+        let echo = start_echo();
+        // the proxy takes a single connection, so the pool has to keep it
+        let (proxy_port, rx) = start_socks5(None);
+        let mut cfg = proxied("localhost", echo, Proxy::Socks5 {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: None,
+            remote_dns: true,
+        });
+        cfg.resolve_interval = Some(Duration::from_millis(100));
+        let pool = ConnectionPool::new(0, 1, false, &cfg).unwrap();
+        let mut conn = pool.acquire().unwrap();
+        let id = conn.id().clone();
+        ping(&mut conn);
+        pool.release(conn);
+        assert_eq!(pool.refresh_addresses().unwrap(), 0);
+        assert_eq!(pool.idle_conns_count(), 1);
+
+        sleep(Duration::from_millis(200));
+        let mut conn = pool.acquire().unwrap();
+        assert_eq!(conn.id(), &id);
+        ping(&mut conn);
+        pool.release(conn);
+        assert_eq!(rx.try_iter().count(), 1);
+        pool.release_all();
+    }
+
+    #[test]
+    fn test_socks5_errors() {
+        let (proxy_port, _rx) = start_socks5(Some(("user", "secret")));
+        let cfg = proxied("127.0.0.1", 1, Proxy::Socks5 {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: auth("user", "wrong"),
+            remote_dns: false,
+        });
+        let e = Connection::connect(&cfg).err().unwrap();
+        match proxy_error(&e) {
+            Some(&ProxyError::AuthFailed(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // nothing listens on the target port
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (proxy_port, _rx) = start_socks5(None);
+        let cfg = proxied("127.0.0.1", closed, Proxy::Socks5 {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: None,
+            remote_dns: false,
+        });
+        let e = Connection::connect(&cfg).err().unwrap();
+        assert_eq!(
+            proxy_error(&e),
+            Some(&ProxyError::TargetFailed("connection refused".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_proxy_unreachable() {
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let cfg = proxied("127.0.0.1", 1, Proxy::HttpConnect {
+            server: "127.0.0.1".to_string(),
+            port: closed,
+            auth: None,
+        });
+        let e = Connection::connect(&cfg).err().unwrap();
+        assert!(proxy_error(&e).is_none());
+        assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_http_connect_auth() {
+        let echo = start_echo();
+        let (proxy_port, rx) = start_http_proxy(Some("dXNlcjpzZWNyZXQ="));
+        let cfg = proxied("127.0.0.1", echo, Proxy::HttpConnect {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: auth("user", "secret"),
+        });
+        let mut conn = Connection::connect(&cfg).unwrap();
+        let request = rx.recv().unwrap();
+        assert!(request.starts_with(&format!("CONNECT 127.0.0.1:{} HTTP/1.1", echo)));
+        ping(&mut conn);
+    }
+
+    #[test]
+    fn test_http_connect_errors() {
+        let (proxy_port, _rx) = start_http_proxy(Some("dXNlcjpzZWNyZXQ="));
+        let cfg = proxied("127.0.0.1", 1, Proxy::HttpConnect {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: None,
+        });
+        let e = Connection::connect(&cfg).err().unwrap();
+        match proxy_error(&e) {
+            Some(&ProxyError::AuthFailed(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let (proxy_port, _rx) = start_http_proxy(None);
+        let cfg = proxied("forbidden.test", 80, Proxy::HttpConnect {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: None,
+        });
+        let e = Connection::connect(&cfg).err().unwrap();
+        assert_eq!(
+            proxy_error(&e),
+            Some(&ProxyError::Refused("HTTP/1.1 403 Forbidden".to_string()))
+        );
+
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (proxy_port, _rx) = start_http_proxy(None);
+        let cfg = proxied("127.0.0.1", closed, Proxy::HttpConnect {
+            server: "127.0.0.1".to_string(),
+            port: proxy_port,
+            auth: None,
+        });
+        let e = Connection::connect(&cfg).err().unwrap();
+        match proxy_error(&e) {
+            Some(&ProxyError::TargetFailed(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
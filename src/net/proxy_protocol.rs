@@ -0,0 +1,233 @@
+//! HAProxy PROXY protocol.  A v1 (text) or v2 (binary) header is sent right after the TCP connect
+//! and before SSL so the backend behind a relay learns the original client address.
+//!
+//! The addresses come from `Config::proxy_protocol_header`, from the header passed to
+//! `Connection::connect_with_proxy_header` / `ConnectionPool::acquire_with_proxy_header`, or
+//! default to the addresses of the TCP connection itself.
+use std::io::{Error, ErrorKind, Result, Write};
+use std::net::{IpAddr, SocketAddr};
+
+/// Signature starting every v2 header
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+
+/// TLV type: application protocol (ALPN)
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+/// TLV type: host name the client connected to (SNI)
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// TLV type: padding, ignored by the receiver
+pub const PP2_TYPE_NOOP: u8 = 0x04;
+/// TLV type: opaque connection id
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+/// TLV type: network namespace
+pub const PP2_TYPE_NETNS: u8 = 0x30;
+
+/// PROXY protocol version
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocolVersion {
+    /// Human readable header. TLVs are not sent
+    V1,
+    /// Binary header with TLVs
+    V2,
+}
+
+/// Type-length-value extension of a v2 header
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyTlv {
+    /// TLV type, e.g. `PP2_TYPE_AUTHORITY`
+    pub kind: u8,
+    /// Value, at most 65535 bytes
+    pub value: Vec<u8>,
+}
+
+/// Addresses announced in the PROXY header
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyHeader {
+    /// Original client address
+    pub source: SocketAddr,
+    /// Address the client connected to
+    pub destination: SocketAddr,
+    /// v2 extensions
+    pub tlvs: Vec<ProxyTlv>,
+}
+
+impl ProxyHeader {
+    /// New header without TLVs
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> ProxyHeader {
+        ProxyHeader {
+            source,
+            destination,
+            tlvs: Vec::new(),
+        }
+    }
+
+    /// Encode the header.  Mixed address families are sent as IPv6 with the IPv4 address mapped
+    pub fn encode(&self, version: ProxyProtocolVersion) -> Result<Vec<u8>> {
+        let (source, destination) = same_family(self.source, self.destination);
+        match version {
+            ProxyProtocolVersion::V1 => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                Ok(format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family,
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                ).into_bytes())
+            }
+            ProxyProtocolVersion::V2 => {
+                let mut body = Vec::new();
+                let family = match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                        body.extend_from_slice(&src.octets());
+                        body.extend_from_slice(&dst.octets());
+                        0x11
+                    }
+                    (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                        body.extend_from_slice(&src.octets());
+                        body.extend_from_slice(&dst.octets());
+                        0x21
+                    }
+                    _ => unreachable!(),
+                };
+                body.extend_from_slice(&source.port().to_be_bytes());
+                body.extend_from_slice(&destination.port().to_be_bytes());
+                for tlv in &self.tlvs {
+                    if tlv.value.len() > u16::MAX as usize {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("PROXY TLV {:#x} is longer than 65535 bytes", tlv.kind),
+                        ));
+                    }
+                    body.push(tlv.kind);
+                    body.extend_from_slice(&(tlv.value.len() as u16).to_be_bytes());
+                    body.extend_from_slice(&tlv.value);
+                }
+                v2_header(0x21, family, &body)
+            }
+        }
+    }
+}
+
+/// Header for a connection without addresses, e.g. over a Unix domain socket. The receiver
+/// uses the addresses of the connection itself
+pub fn encode_local(version: ProxyProtocolVersion) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+        // LOCAL command, unspecified family
+        ProxyProtocolVersion::V2 => v2_header(0x20, 0x00, &[]).unwrap(),
+    }
+}
+
+/// Signature, version and command, family and the length prefixed body
+fn v2_header(version_command: u8, family: u8, body: &[u8]) -> Result<Vec<u8>> {
+    if body.len() > u16::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "PROXY header is longer than 65535 bytes",
+        ));
+    }
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(version_command);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(body);
+    Ok(header)
+}
+
+/// Map an IPv4 address to IPv6 if the other one is IPv6
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    fn to_v6(addr: SocketAddr) -> SocketAddr {
+        match addr.ip() {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+            IpAddr::V6(_) => addr,
+        }
+    }
+    if a.is_ipv4() == b.is_ipv4() {
+        (a, b)
+    } else {
+        (to_v6(a), to_v6(b))
+    }
+}
+
+/// The header for a new connection.  `header` defaults to the local and peer addresses of the
+/// connection, `addrs` is None if there are none (Unix domain socket)
+pub fn header_bytes(
+    version: ProxyProtocolVersion,
+    header: Option<&ProxyHeader>,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+) -> Result<Vec<u8>> {
+    match (header, addrs) {
+        (Some(header), _) => header.encode(version),
+        (None, Some((local, peer))) => ProxyHeader::new(local, peer).encode(version),
+        (None, None) => Ok(encode_local(version)),
+    }
+}
+
+/// Send the header for a new connection, see `header_bytes`
+pub fn send<W: Write>(
+    stream: &mut W,
+    version: ProxyProtocolVersion,
+    header: Option<&ProxyHeader>,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+) -> Result<()> {
+    let bytes = header_bytes(version, header, addrs)?;
+    debug!("Sending PROXY protocol header ({} bytes)", bytes.len());
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{encode_local, ProxyHeader, ProxyProtocolVersion, ProxyTlv, PP2_TYPE_AUTHORITY,
+                V2_SIGNATURE};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_v1() {
+        let header = ProxyHeader::new(addr("192.0.2.10:56324"), addr("198.51.100.1:443"));
+        assert_eq!(
+            header.encode(ProxyProtocolVersion::V1).unwrap(),
+            b"PROXY TCP4 192.0.2.10 198.51.100.1 56324 443\r\n".to_vec()
+        );
+        let header = ProxyHeader::new(addr("[2001:db8::1]:1000"), addr("198.51.100.1:443"));
+        assert_eq!(
+            header.encode(ProxyProtocolVersion::V1).unwrap(),
+            b"PROXY TCP6 2001:db8::1 ::ffff:198.51.100.1 1000 443\r\n".to_vec()
+        );
+        assert_eq!(encode_local(ProxyProtocolVersion::V1), b"PROXY UNKNOWN\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut header = ProxyHeader::new(addr("192.0.2.10:56324"), addr("198.51.100.1:443"));
+        header.tlvs.push(ProxyTlv {
+            kind: PP2_TYPE_AUTHORITY,
+            value: b"example.com".to_vec(),
+        });
+        let bytes = header.encode(ProxyProtocolVersion::V2).unwrap();
+        assert_eq!(&bytes[..12], &V2_SIGNATURE);
+        assert_eq!(bytes[12], 0x21);
+        assert_eq!(bytes[13], 0x11);
+        // 12 bytes of addresses and ports, 3 + 11 of TLV
+        assert_eq!(&bytes[14..16], &[0, 26]);
+        assert_eq!(&bytes[16..20], &[192, 0, 2, 10]);
+        assert_eq!(&bytes[20..24], &[198, 51, 100, 1]);
+        assert_eq!(&bytes[24..28], &[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(&bytes[28..31], &[PP2_TYPE_AUTHORITY, 0, 11]);
+        assert_eq!(&bytes[31..], b"example.com");
+
+        let header = ProxyHeader::new(addr("[2001:db8::1]:1"), addr("[2001:db8::2]:2"));
+        let bytes = header.encode(ProxyProtocolVersion::V2).unwrap();
+        assert_eq!(bytes[13], 0x21);
+        assert_eq!(bytes.len(), 16 + 36);
+
+        let local = encode_local(ProxyProtocolVersion::V2);
+        assert_eq!(&local[12..], &[0x20, 0x00, 0, 0]);
+    }
+}
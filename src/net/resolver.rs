@@ -0,0 +1,154 @@
+//! Name resolution for connections.  `Config::resolver` lets applications and tests plug in
+//! their own resolver, the system resolver is used otherwise. `CachingResolver` saves a lookup
+//! per connect and `Config::hosts` overrides the resolver for individual hosts.
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Resolves a host name to the addresses to connect to
+pub trait Resolver: Send + Sync {
+    /// All the addresses for `host`, in order of preference
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>>;
+
+    /// Same as `resolve`, also returning how long the answer may be cached if the resolver
+    /// knows it. The system resolver does not report TTLs
+    fn resolve_ttl(&self, host: &str, port: u16) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+        self.resolve(host, port).map(|addrs| (addrs, None))
+    }
+}
+
+/// Resolver using the system resolver (getaddrinfo)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Resolver caching the answers of another resolver.  An entry is kept for the TTL reported by
+/// the inner resolver, capped at the `ttl` given to `new`. Failed lookups are not cached
+pub struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    ttl: Duration,
+    cache: Mutex<HashMap<(String, u16), CacheEntry>>,
+}
+
+/// Cached answer
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+impl CachingResolver {
+    /// New instance caching the answers of `inner` for at most `ttl`
+    pub fn new(inner: Arc<dyn Resolver>, ttl: Duration) -> CachingResolver {
+        CachingResolver {
+            inner,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forget the cached answer for `host`, the next lookup goes to the inner resolver
+    pub fn invalidate(&self, host: &str, port: u16) {
+        self.cache.lock().unwrap().remove(&(host.to_string(), port));
+    }
+
+    /// Forget all cached answers
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        self.resolve_ttl(host, port).map(|(addrs, _)| addrs)
+    }
+
+    fn resolve_ttl(&self, host: &str, port: u16) -> Result<(Vec<SocketAddr>, Option<Duration>)> {
+        let key = (host.to_string(), port);
+        let now = Instant::now();
+        if let Some(entry) = self.cache.lock().unwrap().get(&key) {
+            if entry.expires > now {
+                return Ok((entry.addrs.clone(), Some(entry.expires - now)));
+            }
+        }
+        // resolve outside of the lock so a slow lookup does not stall other hosts
+        let (addrs, ttl) = self.inner.resolve_ttl(host, port)?;
+        let ttl = ttl.map_or(self.ttl, |ttl| ttl.min(self.ttl));
+        debug!("Resolved {}:{} to {:?}, caching for {:?}", host, port, addrs, ttl);
+        self.cache.lock().unwrap().insert(
+            key,
+            CacheEntry {
+                addrs: addrs.clone(),
+                expires: now + ttl,
+            },
+        );
+        Ok((addrs, Some(ttl)))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Result;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::{CachingResolver, Resolver};
+
+    /// Resolver returning a settable list of addresses and counting the lookups
+    pub struct TestResolver {
+        /// Addresses returned by `resolve`
+        pub addrs: Mutex<Vec<SocketAddr>>,
+        /// Number of lookups
+        pub lookups: AtomicUsize,
+    }
+
+    impl TestResolver {
+        /// New instance returning `addrs`
+        pub fn new(addrs: Vec<SocketAddr>) -> TestResolver {
+            TestResolver {
+                addrs: Mutex::new(addrs),
+                lookups: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Resolver for TestResolver {
+        fn resolve(&self, _host: &str, _port: u16) -> Result<Vec<SocketAddr>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(self.addrs.lock().unwrap().clone())
+        }
+    }
+
+    #[test]
+    fn test_caching_resolver() {
+        let first: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let second: SocketAddr = "192.0.2.2:80".parse().unwrap();
+        let inner = Arc::new(TestResolver::new(vec![first]));
+        let cache = CachingResolver::new(inner.clone(), Duration::from_millis(200));
+        assert_eq!(cache.resolve("example.test", 80).unwrap(), vec![first]);
+        *inner.addrs.lock().unwrap() = vec![second];
+        assert_eq!(cache.resolve("example.test", 80).unwrap(), vec![first]);
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 1);
+
+        // a different port is a different entry
+        cache.resolve("example.test", 81).unwrap();
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 2);
+
+        sleep(Duration::from_millis(300));
+        assert_eq!(cache.resolve("example.test", 80).unwrap(), vec![second]);
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 3);
+
+        *inner.addrs.lock().unwrap() = vec![first];
+        cache.invalidate("example.test", 80);
+        assert_eq!(cache.resolve("example.test", 80).unwrap(), vec![first]);
+    }
+}
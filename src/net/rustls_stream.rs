@@ -0,0 +1,470 @@
+//! TLS with rustls instead of OpenSSL, compiled with the `rustls` feature when `ssl` is off.
+//!
+//! `SharedSslStream` works like the OpenSSL one: the socket is switched to non-blocking mode after
+//! the handshake and each read or write holds the lock on the session only for a single
+//! non-blocking attempt, so a reader blocked waiting for data does not stop the writer.
+//!
+//! The TLS settings of `Config` mean the same as with OpenSSL. The system's CAs are loaded with
+//! `rustls-native-certs`. Settings rustls cannot honor fail the connector build instead of being
+//! ignored: `tls_cipher_list`, `tls13_ciphersuites` and `tls_groups` (OpenSSL syntax), `tls_pins`,
+//! `ocsp_stapling`, `verify_depth`, `tls_session_cache`, PKCS#12 identities, encrypted keys, and
+//! TLS versions below 1.2. Connections sharing a `TlsConnector`, like those of a pool, resume
+//! each other's sessions: rustls only resumes a session with the connector it was negotiated with.
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{verify_server_name, WebPkiServerVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::ParsedCertificate;
+use rustls::version::{TLS12, TLS13};
+use rustls::{CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct,
+             HandshakeKind, RootCertStore, SignatureScheme, StreamOwned, SupportedProtocolVersion};
+use socket2::SockRef;
+
+use crate::net::config::Config;
+use crate::net::conn;
+use crate::net::socket;
+use crate::net::tls::{self, TlsIdentity, TlsInfo, TlsVersion, TrustAnchor};
+
+/// A handle to a TLS session shared by the reader and the writer of a `Connection`.
+/// The session runs over a TCP or a Unix domain socket
+pub struct SharedSslStream<S: Read + Write = TcpStream> {
+    inner: Arc<Mutex<StreamOwned<ClientConnection, S>>>,
+    fd: RawFd,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<S: Read + Write + AsFd + AsRawFd> SharedSslStream<S> {
+    /// Wraps a session which completed the handshake over `socket`.  The socket is put in
+    /// non-blocking mode, the timeouts are enforced by this type instead
+    pub fn new(
+        session: ClientConnection,
+        socket: S,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+    ) -> Result<SharedSslStream<S>> {
+        SockRef::from(&socket).set_nonblocking(true)?;
+        let fd = socket.as_raw_fd();
+        Ok(SharedSslStream {
+            inner: Arc::new(Mutex::new(StreamOwned::new(session, socket))),
+            fd,
+            read_timeout,
+            write_timeout,
+        })
+    }
+
+    /// Another handle to the same TLS session
+    pub fn try_clone(&self) -> SharedSslStream<S> {
+        SharedSslStream {
+            inner: Arc::clone(&self.inner),
+            fd: self.fd,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        }
+    }
+
+    /// Run `f` with the TLS session locked
+    pub fn with_stream<T, F: FnOnce(&mut StreamOwned<ClientConnection, S>) -> T>(&self, f: F) -> T {
+        f(&mut self.inner.lock().unwrap())
+    }
+
+    /// Send the close_notify alert.  Best effort, the peer is not waited for
+    pub fn shutdown(&self) {
+        let mut stream = self.inner.lock().unwrap();
+        stream.conn.send_close_notify();
+        let _ = write_records(&mut stream);
+    }
+
+    /// Details of the TLS session
+    pub fn tls_info(&self) -> TlsInfo {
+        self.with_stream(|s| TlsInfo::from_rustls(&s.conn))
+    }
+
+    /// The ALPN protocol the server selected
+    pub fn negotiated_protocol(&self) -> Option<Vec<u8>> {
+        self.with_stream(|s| s.conn.alpn_protocol().map(|p| p.to_vec()))
+    }
+
+    /// Was the session resumed instead of negotiated with a full handshake
+    pub fn session_reused(&self) -> bool {
+        self.with_stream(|s| s.conn.handshake_kind() == Some(HandshakeKind::Resumed))
+    }
+
+    /// Write the pending records to the socket, waiting outside of the lock
+    fn send(&self, deadline: Option<Instant>) -> Result<()> {
+        while !write_records(&mut self.inner.lock().unwrap())? {
+            socket::wait_ready(self.fd, libc::POLLOUT, deadline)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the pending records until the socket would block. Returns true if all were written
+fn write_records<S: Read + Write>(stream: &mut StreamOwned<ClientConnection, S>) -> Result<bool> {
+    while stream.conn.wants_write() {
+        match stream.conn.write_tls(&mut stream.sock) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// A single non-blocking read: decrypted data if there is some, else the records which have
+/// arrived on the socket are processed. None if it has to wait for the socket
+fn try_read<S: Read + Write>(
+    stream: &mut StreamOwned<ClientConnection, S>,
+    buf: &mut [u8],
+) -> Result<Option<usize>> {
+    loop {
+        match stream.conn.reader().read(buf) {
+            // 0 after a clean close from the peer
+            Ok(n) => return Ok(Some(n)),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+            // EOF without close_notify
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Some(0)),
+            Err(e) => return Err(e),
+        }
+        match stream.conn.read_tls(&mut stream.sock) {
+            // EOF is reported by the reader
+            Ok(_) => {
+                if let Err(e) = stream.conn.process_new_packets() {
+                    // the alert for the peer, best effort
+                    let _ = write_records(stream);
+                    return Err(Error::new(ErrorKind::InvalidData, e));
+                }
+                // e.g. key update responses. Left for the next write if the socket is full
+                write_records(stream)?;
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+impl<S: Read + Write> AsRawFd for SharedSslStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<S: Read + Write + AsFd + AsRawFd> Read for SharedSslStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(n) = try_read(&mut self.inner.lock().unwrap(), buf)? {
+                return Ok(n);
+            }
+            socket::wait_ready(self.fd, libc::POLLIN, deadline)?;
+        }
+    }
+}
+
+impl<S: Read + Write + AsFd + AsRawFd> Write for SharedSslStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let deadline = self.write_timeout.map(|t| Instant::now() + t);
+        let n = self.inner.lock().unwrap().conn.writer().write(buf)?;
+        self.send(deadline)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let deadline = self.write_timeout.map(|t| Instant::now() + t);
+        self.send(deadline)
+    }
+}
+
+/// Runs the client TLS handshake over a connected socket, blocking within its timeouts
+pub(crate) fn tls_connect<S: Read + Write + AsFd + AsRawFd>(
+    config: &Config,
+    mut socket: S,
+) -> Result<SharedSslStream<S>> {
+    let client = match config.tls_connector {
+        Some(ref connector) => connector.get(),
+        None => Arc::new(ssl_connector(config)?),
+    };
+    let name = ServerName::try_from(conn::tls_server_name(config)).map_err(|e| {
+        Error::new(ErrorKind::InvalidInput, format!("invalid TLS server name: {}", e))
+    })?;
+    let mut session = ClientConnection::new(client, name).map_err(tls_error)?;
+    while session.is_handshaking() {
+        session.complete_io(&mut socket).map_err(|e| {
+            Error::other(format!("An SSL error occurred. ({})", e))
+        })?;
+    }
+    tls::check_alpn(config, session.alpn_protocol())?;
+    SharedSslStream::new(session, socket, config.read_timeout, config.write_timeout)
+}
+
+/// Builds the rustls client config for the verify, certificate, key and CA settings in the config.
+pub(crate) fn ssl_connector(config: &Config) -> Result<ClientConfig> {
+    check_supported(config)?;
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&protocol_versions(config)?)
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(Verifier::new(config, provider)?));
+    let mut client = match client_identity(config)? {
+        Some((certs, key)) => builder.with_client_auth_cert(certs, key).map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+    if let Some(ref protocols) = config.alpn_protocols {
+        tls::alpn_wire_format(protocols)?;
+        client.alpn_protocols = protocols.clone();
+    }
+    Ok(client)
+}
+
+/// Fails for the settings only the OpenSSL backend supports
+fn check_supported(config: &Config) -> Result<()> {
+    let openssl_only = [
+        ("tls_cipher_list", config.tls_cipher_list.is_some()),
+        ("tls13_ciphersuites", config.tls13_ciphersuites.is_some()),
+        ("tls_groups", config.tls_groups.is_some()),
+        ("tls_pins", config.tls_pins.is_some()),
+        ("ocsp_stapling", config.ocsp_stapling.unwrap_or(false)),
+        ("verify_depth", config.verify_depth.unwrap_or(0) > 0),
+        ("tls_session_cache", config.tls_session_cache.is_some()),
+    ];
+    match openssl_only.iter().find(|&&(_, set)| set) {
+        Some(&(name, _)) => Err(unsupported(name)),
+        None => Ok(()),
+    }
+}
+
+/// Error for a setting the rustls backend does not support
+fn unsupported(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("{} is not supported by the rustls backend", what),
+    )
+}
+
+/// The versions between `Config::tls_min_version` and `Config::tls_max_version`
+fn protocol_versions(config: &Config) -> Result<Vec<&'static SupportedProtocolVersion>> {
+    let min = config.tls_min_version.unwrap_or(TlsVersion::Tls12);
+    let max = config.tls_max_version.unwrap_or(TlsVersion::Tls13);
+    let mut versions = Vec::new();
+    if min <= TlsVersion::Tls12 && max >= TlsVersion::Tls12 {
+        versions.push(&TLS12);
+    }
+    if max >= TlsVersion::Tls13 {
+        versions.push(&TLS13);
+    }
+    if versions.is_empty() {
+        return Err(unsupported("TLS below 1.2"));
+    }
+    Ok(versions)
+}
+
+/// Client certificate chain and key from `Config::tls_identity` or the certificate and key files
+fn client_identity(
+    config: &Config,
+) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+    let (certs, key) = match config.tls_identity {
+        Some(TlsIdentity::Pem {
+            ref certs,
+            ref key,
+            ref passphrase,
+        }) => {
+            if passphrase.is_some() {
+                return Err(unsupported("an encrypted private key"));
+            }
+            (pem_certs(certs)?, pem_key(key)?)
+        }
+        Some(TlsIdentity::Der { ref certs, ref key }) => {
+            let certs = certs.iter().map(|c| CertificateDer::from(c.clone())).collect();
+            let key = PrivateKeyDer::try_from(key.clone())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            (certs, key)
+        }
+        Some(TlsIdentity::Pkcs12 { .. }) => return Err(unsupported("a PKCS#12 identity")),
+        None => match (&config.certificate_file, &config.private_key_file) {
+            (Some(cert), Some(key)) => (pem_file(cert)?, pem_key(&fs::read(key)?)?),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "certificate_file and private_key_file must be set together",
+                ))
+            }
+        },
+    };
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "TLS identity has no certificate"));
+    }
+    Ok(Some((certs, key)))
+}
+
+/// The trusted CAs: `Config::ca_file`, `Config::trust_anchors` and, if `system` is set, the
+/// system's unless `TrustAnchors::system_roots` is off
+fn root_store(config: &Config, system: bool) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    if system && config.trust_anchors.as_ref().is_none_or(|t| t.system_roots) {
+        let native = rustls_native_certs::load_native_certs();
+        for e in native.errors {
+            warn!("Failed to load the system's CA certificates: {}", e);
+        }
+        roots.add_parsable_certificates(native.certs);
+    }
+    if let Some(ref path) = config.ca_file {
+        add_certs(&mut roots, pem_file(path)?)?;
+    }
+    for anchor in config.trust_anchors.iter().flat_map(|t| t.anchors.iter()) {
+        match *anchor {
+            TrustAnchor::Pem(ref pem) => add_certs(&mut roots, pem_certs(pem)?)?,
+            TrustAnchor::Der(ref der) => add_certs(&mut roots, vec![der.clone().into()])?,
+            TrustAnchor::File(ref path) => add_certs(&mut roots, pem_file(path)?)?,
+            // every certificate file, not only the ones named by subject hash
+            TrustAnchor::Dir(ref path) => {
+                for entry in fs::read_dir(path)? {
+                    let path = entry?.path();
+                    if let Ok(certs) = pem_file(&path) {
+                        roots.add_parsable_certificates(certs);
+                    }
+                }
+            }
+        }
+    }
+    Ok(roots)
+}
+
+/// Add `certs` to the trusted CAs
+fn add_certs(roots: &mut RootCertStore, certs: Vec<CertificateDer<'static>>) -> Result<()> {
+    for cert in certs {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(())
+}
+
+/// The certificates of a PEM bundle
+fn pem_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid PEM: {}", e)))?;
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "no certificate in PEM"));
+    }
+    Ok(certs)
+}
+
+/// The certificates of a PEM bundle file
+fn pem_file(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    pem_certs(&fs::read(path)?)
+}
+
+/// The first private key of a PEM file, PKCS#8, PKCS#1 or SEC1
+fn pem_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("invalid PEM private key: {}", e)))
+}
+
+/// Converts a rustls error into an io::Error
+fn tls_error(e: rustls::Error) -> Error {
+    Error::other(format!("An SSL error occurred. ({})", e))
+}
+
+/// Checks the server certificate as `Config::verify` and `Config::verify_hostname` ask: the
+/// chain against the trusted CAs and the name, either or both
+#[derive(Debug)]
+struct Verifier {
+    /// verifies the chain, and the name unless `hostname` is off. None without `Config::verify`
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    hostname: bool,
+    provider: Arc<CryptoProvider>,
+}
+
+impl Verifier {
+    fn new(config: &Config, provider: Arc<CryptoProvider>) -> Result<Verifier> {
+        let verify = config.verify.unwrap_or(false);
+        // the CA files are read without `verify` too, so broken paths are caught
+        let roots = root_store(config, verify)?;
+        let chain = if verify {
+            if roots.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "no trusted CA certificates"));
+            }
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()
+                    .map_err(|e| Error::other(format!("An SSL error occurred. ({})", e)))?;
+            Some(verifier)
+        } else {
+            None
+        };
+        Ok(Verifier {
+            chain,
+            hostname: conn::verify_hostname(config),
+            provider,
+        })
+    }
+}
+
+/// Is `e` the certificate not matching the server name
+fn is_name_error(e: &CertificateError) -> bool {
+    matches!(
+        *e,
+        CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. }
+    )
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp: &[u8],
+        now: UnixTime,
+    ) -> ::std::result::Result<ServerCertVerified, rustls::Error> {
+        if let Some(ref chain) = self.chain {
+            // the name is checked once the chain is valid, a mismatch means the chain is fine
+            let result =
+                chain.verify_server_cert(end_entity, intermediates, server_name, ocsp, now);
+            return match result {
+                Err(rustls::Error::InvalidCertificate(ref e))
+                    if !self.hostname && is_name_error(e) => Ok(ServerCertVerified::assertion()),
+                result => result,
+            };
+        }
+        if self.hostname {
+            verify_server_name(&ParsedCertificate::try_from(end_entity)?, server_name)?;
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> ::std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> ::std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,618 @@
+//! Socket setup shared by the blocking and async connections: name resolution, Happy Eyeballs
+//! connect, local bind and the socket options from `Config` (TCP_NODELAY, keepalive, buffer sizes,
+//! linger and IP TOS), and Unix domain sockets.
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+
+use crate::net::config;
+use crate::net::resolver::{Resolver, SystemResolver};
+
+/// Default delay before racing the next address
+const DEFAULT_HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+
+/// Longest single wait in `wait_ready`.  The other half of a shared TLS session may consume the
+/// records we are waiting for (e.g. during a renegotiation) so the session is polled again at
+/// least this often.
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+const MAX_POLL_MS: u64 = 100;
+
+/// `Config::server` prefix selecting a Unix domain socket, e.g. `unix:///run/app.sock`
+const UNIX_SCHEME: &str = "unix://";
+
+/// Path of the Unix domain socket to connect to, from `Config::unix_socket` or a `unix://`
+/// server. None for TCP
+pub fn unix_path(config: &config::Config) -> Option<PathBuf> {
+    if let Some(ref path) = config.unix_socket {
+        return Some(path.clone());
+    }
+    match config.server {
+        Some(ref server) if server.starts_with(UNIX_SCHEME) => {
+            Some(PathBuf::from(&server[UNIX_SCHEME.len()..]))
+        }
+        _ => None,
+    }
+}
+
+/// Where the config connects to, for messages: `unix:<path>` or `<server>:<port>`
+pub fn endpoint(config: &config::Config) -> String {
+    match unix_path(config) {
+        Some(path) => format!("unix:{}", path.display()),
+        None => format!(
+            "{}:{}",
+            config.server.as_deref().unwrap_or(""),
+            config.port.unwrap_or(0)
+        ),
+    }
+}
+
+/// Connect to the Unix domain socket at `path` within `Config::connect_timeout`
+pub fn connect_unix(config: &config::Config, path: &Path) -> Result<UnixStream> {
+    debug!("Connecting to unix:{}", path.display());
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    let addr = SockAddr::unix(path)?;
+    match config.connect_timeout {
+        Some(timeout) => socket.connect_timeout(&addr, timeout)?,
+        None => socket.connect(&addr)?,
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    info!("Connected to unix:{}", path.display());
+    Ok(socket.into())
+}
+
+/// Async version of `connect_unix`
+#[cfg(feature = "async")]
+pub async fn connect_unix_async(
+    config: &config::Config,
+    path: &Path,
+) -> Result<tokio::net::UnixStream> {
+    debug!("Connecting to unix:{}", path.display());
+    let stream = match config.connect_timeout {
+        Some(t) => tokio::time::timeout(t, tokio::net::UnixStream::connect(path))
+            .await
+            .unwrap_or_else(|_| {
+                Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("Timed out connecting to unix:{}", path.display()),
+                ))
+            })?,
+        None => tokio::net::UnixStream::connect(path).await?,
+    };
+    info!("Connected to unix:{}", path.display());
+    Ok(stream)
+}
+
+/// Resolve the server and port in the config with `Config::hosts` or else `Config::resolver`.
+/// The addresses are interleaved by family as RFC 8305 recommends
+pub fn resolve(config: &config::Config) -> Result<Vec<SocketAddr>> {
+    let host: &str = &config.server.clone().unwrap();
+    let port = config.port.unwrap();
+    if let Some(ips) = config.hosts.as_ref().and_then(|hosts| hosts.get(host)) {
+        return Ok(interleave(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()));
+    }
+    let addrs = match config.resolver {
+        Some(ref resolver) => resolver.resolve(host, port)?,
+        None => SystemResolver.resolve(host, port)?,
+    };
+    Ok(interleave(addrs))
+}
+
+/// Alternate address families, starting with the family of the first address
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut preferred, mut other): (VecDeque<SocketAddr>, VecDeque<SocketAddr>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return result,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+}
+
+/// Delay between starting connection attempts
+fn happy_eyeballs_delay(config: &config::Config) -> Duration {
+    config.happy_eyeballs_delay.unwrap_or_else(|| {
+        Duration::from_millis(DEFAULT_HAPPY_EYEBALLS_DELAY_MS)
+    })
+}
+
+/// Error returned when `Config::connect_timeout` expires
+fn connect_timed_out(config: &config::Config) -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        format!(
+            "Timed out connecting to {}:{}",
+            config.server.clone().unwrap(),
+            config.port.unwrap()
+        ),
+    )
+}
+
+/// Creates an unconnected socket for `addr`, bound to `Config::local_addr` and with the
+/// socket options from the config applied
+pub fn new_socket(config: &config::Config, addr: &SocketAddr) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(local_addr) = config.local_addr {
+        socket.bind(&local_addr.into())?;
+    }
+    apply_options(&socket, config, addr.is_ipv6())?;
+    Ok(socket)
+}
+
+/// Apply the socket options from the config
+pub fn apply_options(socket: &Socket, config: &config::Config, ipv6: bool) -> Result<()> {
+    if let Some(nodelay) = config.tcp_nodelay {
+        socket.set_tcp_nodelay(nodelay)?;
+    }
+    if config.keepalive.unwrap_or(false) {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(idle) = config.keepalive_idle {
+            keepalive = keepalive.with_time(idle);
+        }
+        if let Some(interval) = config.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        if let Some(count) = config.keepalive_count {
+            keepalive = keepalive.with_retries(count);
+        }
+        socket.set_keepalive(true)?;
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(linger) = config.linger {
+        socket.set_linger(Some(linger))?;
+    }
+    if let Some(tos) = config.ip_tos {
+        if ipv6 {
+            set_tclass_v6(socket, tos)?;
+        } else {
+            socket.set_tos_v4(tos)?;
+        }
+    }
+    Ok(())
+}
+
+/// IPV6_TCLASS, the IPv6 equivalent of IP_TOS
+fn set_tclass_v6(socket: &Socket, tclass: u32) -> Result<()> {
+    let value = tclass as libc::c_int;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &value as *const libc::c_int as *const libc::c_void,
+            ::std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Error returned when the server did not resolve to any address
+fn no_addresses(config: &config::Config) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!(
+            "{}:{} did not resolve to any address",
+            config.server.clone().unwrap(),
+            config.port.unwrap()
+        ),
+    )
+}
+
+/// Connect to the server in the config.  The resolved addresses are raced (Happy Eyeballs,
+/// RFC 8305): the next address is tried when the previous attempt fails or has not succeeded
+/// within `Config::happy_eyeballs_delay`. The first connected socket wins, the others are closed.
+/// Returns the connected stream and the address which won
+pub fn connect(config: &config::Config) -> Result<(TcpStream, SocketAddr)> {
+    let addrs = resolve(config)?;
+    let delay = happy_eyeballs_delay(config);
+    let deadline = config.connect_timeout.map(|t| Instant::now() + t);
+    let mut pending: Vec<(Socket, SocketAddr)> = Vec::new();
+    let mut next = 0;
+    let mut next_start = Instant::now();
+    let mut last_err = None;
+    loop {
+        let now = Instant::now();
+        if deadline.is_some_and(|d| now >= d) {
+            return Err(connect_timed_out(config));
+        }
+        // start the next attempt
+        if next < addrs.len() && (pending.is_empty() || now >= next_start) {
+            let addr = addrs[next];
+            next += 1;
+            next_start = now + delay;
+            debug!("Connecting to {}", addr);
+            let socket = match new_socket(config, &addr) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+            socket.set_nonblocking(true)?;
+            match socket.connect(&addr.into()) {
+                Ok(()) => return connected(socket, addr),
+                Err(ref e) if e.raw_os_error() == Some(libc::EINPROGRESS) ||
+                                  e.kind() == ErrorKind::WouldBlock => {
+                    pending.push((socket, addr));
+                }
+                Err(e) => {
+                    debug!("Failed to connect to {}: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+            continue;
+        }
+        if pending.is_empty() {
+            return Err(last_err.unwrap_or_else(|| no_addresses(config)));
+        }
+
+        // wait for an attempt to finish, the next attempt to start or the deadline
+        let mut wait_until = deadline;
+        if next < addrs.len() {
+            wait_until = Some(wait_until.map_or(next_start, |d| d.min(next_start)));
+        }
+        let timeout_ms = match wait_until {
+            Some(until) => {
+                let wait = until.saturating_duration_since(now);
+                wait.as_micros().div_ceil(1000) as libc::c_int
+            }
+            None => -1,
+        };
+        let mut pfds: Vec<libc::pollfd> = pending
+            .iter()
+            .map(|(socket, _)| {
+                libc::pollfd {
+                    fd: socket.as_raw_fd(),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                }
+            })
+            .collect();
+        let rc = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) };
+        if rc < 0 {
+            let e = Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+            continue;
+        }
+        for i in (0..pfds.len()).rev() {
+            if pfds[i].revents == 0 {
+                continue;
+            }
+            let (socket, addr) = pending.remove(i);
+            match socket.take_error() {
+                Ok(None) => return connected(socket, addr),
+                Ok(Some(e)) | Err(e) => {
+                    debug!("Failed to connect to {}: {}", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+    }
+}
+
+/// Switch the winning socket back to blocking mode
+fn connected(socket: Socket, addr: SocketAddr) -> Result<(TcpStream, SocketAddr)> {
+    socket.set_nonblocking(false)?;
+    info!("Connected to {}", addr);
+    Ok((socket.into(), addr))
+}
+
+/// Async version of `connect`
+#[cfg(feature = "async")]
+pub async fn connect_async(
+    config: &config::Config,
+) -> Result<(tokio::net::TcpStream, SocketAddr)> {
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    let addrs = {
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || resolve(&config))
+            .await
+            .map_err(Error::other)??
+    };
+    let delay = happy_eyeballs_delay(config);
+    let race = async {
+        let (tx, mut rx) = mpsc::channel(addrs.len().max(1));
+        let mut next = 0;
+        let mut pending = 0;
+        let mut last_err = None;
+        loop {
+            if next < addrs.len() {
+                let addr = addrs[next];
+                next += 1;
+                let socket = new_socket(config, &addr).and_then(|socket| {
+                    socket.set_nonblocking(true)?;
+                    Ok(tokio::net::TcpSocket::from_std_stream(socket.into()))
+                });
+                match socket {
+                    Ok(socket) => {
+                        pending += 1;
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            // a late winner is closed when the receiver is gone
+                            let _ = tx.send((addr, socket.connect(addr).await)).await;
+                        });
+                    }
+                    Err(e) => {
+                        last_err = Some(e);
+                        continue;
+                    }
+                }
+            }
+            if pending == 0 {
+                return Err(last_err.unwrap_or_else(|| no_addresses(config)));
+            }
+            let result = if next < addrs.len() {
+                match timeout(delay, rx.recv()).await {
+                    Ok(result) => result,
+                    // start the next attempt
+                    Err(_) => continue,
+                }
+            } else {
+                rx.recv().await
+            };
+            match result {
+                Some((addr, Ok(stream))) => {
+                    info!("Connected to {}", addr);
+                    return Ok((stream, addr));
+                }
+                Some((addr, Err(e))) => {
+                    debug!("Failed to connect to {}: {}", addr, e);
+                    pending -= 1;
+                    last_err = Some(e);
+                }
+                None => return Err(last_err.unwrap_or_else(|| no_addresses(config))),
+            }
+        }
+    };
+    match config.connect_timeout {
+        Some(t) => timeout(t, race).await.unwrap_or_else(|_| Err(connect_timed_out(config))),
+        None => race.await,
+    }
+}
+
+/// Wait until the non-blocking socket `fd` is ready for `events` or the deadline has passed.
+/// Returns after at most `MAX_POLL_MS` even if it is not ready, callers retry
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+pub(crate) fn wait_ready(fd: RawFd, events: libc::c_short, deadline: Option<Instant>) -> Result<()> {
+    let mut wait = Duration::from_millis(MAX_POLL_MS);
+    if let Some(deadline) = deadline {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::new(ErrorKind::TimedOut, "SSL stream timed out"));
+        }
+        wait = wait.min(deadline - now);
+    }
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    // round up so a sub-millisecond remainder does not spin
+    let ms = (wait.as_micros() as libc::c_int + 999) / 1000;
+    let rc = unsafe { libc::poll(&mut pfd, 1, ms) };
+    if rc < 0 {
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Result;
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use socket2::SockRef;
+
+    use crate::net::config;
+    use crate::net::resolver::Resolver;
+
+    /// Resolver returning a fixed list of addresses
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolver for StaticResolver {
+        fn resolve(&self, _host: &str, _port: u16) -> Result<Vec<SocketAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// Config for `server` resolving to `addrs`
+    fn racing_config(addrs: Vec<SocketAddr>) -> config::Config {
+        config::Config {
+            server: Some("example.test".to_string()),
+            port: Some(addrs[0].port()),
+            resolver: Some(Arc::new(StaticResolver(addrs))),
+            happy_eyeballs_delay: Some(Duration::from_millis(100)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_interleave() {
+        let v6a: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let v6b: SocketAddr = "[2001:db8::2]:80".parse().unwrap();
+        let v4a: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let v4b: SocketAddr = "192.0.2.2:80".parse().unwrap();
+        let v4c: SocketAddr = "192.0.2.3:80".parse().unwrap();
+        assert_eq!(
+            super::interleave(vec![v6a, v6b, v4a, v4b, v4c]),
+            vec![v6a, v4a, v6b, v4b, v4c]
+        );
+        assert_eq!(
+            super::interleave(vec![v4a, v4b, v6a]),
+            vec![v4a, v6a, v4b]
+        );
+        assert_eq!(super::interleave(vec![]), vec![]);
+    }
+
+    #[test]
+    fn test_happy_eyeballs_skips_refused() {
+        // nothing listens on the first address, the refusal starts the next attempt at once
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        let cfg = racing_config(vec![refused, good]);
+        let (_, addr) = super::connect(&cfg).unwrap();
+        assert_eq!(addr, good);
+    }
+
+    #[test]
+    fn test_happy_eyeballs_races_unresponsive() {
+        // TEST-NET address which never answers, the next address starts after the delay
+        let blackhole: SocketAddr = "192.0.2.1:9".parse().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        let mut cfg = racing_config(vec![blackhole, good]);
+        cfg.port = Some(good.port());
+        let start = Instant::now();
+        let (_, addr) = super::connect(&cfg).unwrap();
+        assert_eq!(addr, good);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_hosts_override() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        // the resolver would send us to a black hole
+        let mut cfg = racing_config(vec!["192.0.2.1:9".parse().unwrap()]);
+        cfg.port = Some(good.port());
+        let mut hosts = HashMap::new();
+        hosts.insert("example.test".to_string(), vec![good.ip()]);
+        cfg.hosts = Some(hosts);
+        assert_eq!(super::resolve(&cfg).unwrap(), vec![good]);
+        let (_, addr) = super::connect(&cfg).unwrap();
+        assert_eq!(addr, good);
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let blackhole: SocketAddr = "192.0.2.1:9".parse().unwrap();
+        let mut cfg = racing_config(vec![blackhole]);
+        cfg.connect_timeout = Some(Duration::from_millis(200));
+        let start = Instant::now();
+        let e = super::connect(&cfg).err().unwrap();
+        // without a route the attempt may fail before the timeout
+        if e.kind() == ::std::io::ErrorKind::TimedOut {
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_unix_path() {
+        let mut cfg = config::Config {
+            server: Some("unix:///run/app.sock".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(super::unix_path(&cfg), Some(PathBuf::from("/run/app.sock")));
+        cfg.unix_socket = Some(PathBuf::from("/tmp/other.sock"));
+        assert_eq!(super::unix_path(&cfg), Some(PathBuf::from("/tmp/other.sock")));
+        cfg.unix_socket = None;
+        cfg.server = Some("127.0.0.1".to_string());
+        assert_eq!(super::unix_path(&cfg), None);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_happy_eyeballs_async() {
+        let blackhole: SocketAddr = "192.0.2.1:9".parse().unwrap();
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        let cfg = racing_config(vec![blackhole, refused, good]);
+        let (_, addr) = super::connect_async(&cfg).await.unwrap();
+        assert_eq!(addr, good);
+    }
+
+    #[test]
+    fn test_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_port = {
+            // find a free port for the local bind
+            let l = TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap().port()
+        };
+        let cfg = config::Config {
+            server: Some("127.0.0.1".to_string()),
+            port: Some(listener.local_addr().unwrap().port()),
+            tcp_nodelay: Some(true),
+            keepalive: Some(true),
+            keepalive_idle: Some(Duration::from_secs(30)),
+            keepalive_interval: Some(Duration::from_secs(5)),
+            keepalive_count: Some(4),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+            linger: Some(Duration::from_secs(1)),
+            ip_tos: Some(0x10),
+            local_addr: Some(SocketAddr::from(([127, 0, 0, 1], local_port))),
+            ..Default::default()
+        };
+        let (stream, _) = super::connect(&cfg).unwrap();
+        let socket = SockRef::from(&stream);
+        assert_eq!(socket.tcp_nodelay().unwrap(), true);
+        assert_eq!(socket.keepalive().unwrap(), true);
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.tcp_keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.tcp_keepalive_retries().unwrap(), 4);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert_eq!(socket.linger().unwrap(), Some(Duration::from_secs(1)));
+        assert_eq!(socket.tos_v4().unwrap(), 0x10);
+        assert_eq!(stream.local_addr().unwrap().port(), local_port);
+    }
+
+    #[test]
+    fn test_default_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = config::Config {
+            server: Some("127.0.0.1".to_string()),
+            port: Some(listener.local_addr().unwrap().port()),
+            ..Default::default()
+        };
+        let (stream, _) = super::connect(&cfg).unwrap();
+        let socket = SockRef::from(&stream);
+        assert_eq!(socket.tcp_nodelay().unwrap(), false);
+        assert_eq!(socket.keepalive().unwrap(), false);
+    }
+}
@@ -0,0 +1,214 @@
+//! SSL stream which can be read and written from separate threads at the same time.
+//!
+//! The socket is switched to non-blocking mode after the handshake.  Each read or write holds the
+//! lock on the SSL session only for a single non-blocking `SSL_read`/`SSL_write` attempt, waiting for
+//! the socket happens outside of the lock.  A reader blocked waiting for data therefore does not stop
+//! the writer.  When OpenSSL asks for the other direction (e.g. `SSL_write` wanting to read during a
+//! renegotiation) the wait is for that direction, so renegotiation keeps working.
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use openssl::ssl::{self, ErrorCode, SslStream};
+use socket2::SockRef;
+
+use crate::net::socket;
+use crate::net::tls::TlsInfo;
+
+/// A handle to an SSL session shared by the reader and the writer of a `Connection`.
+/// The session runs over a TCP or a Unix domain socket
+pub struct SharedSslStream<S = TcpStream> {
+    inner: Arc<Mutex<SslStream<S>>>,
+    fd: RawFd,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<S: Read + Write + AsFd + AsRawFd> SharedSslStream<S> {
+    /// Wraps a connected SSL stream.  The socket is put in non-blocking mode, the timeouts are
+    /// enforced by this type instead
+    pub fn new(
+        stream: SslStream<S>,
+        read_timeout: Option<Duration>,
+        write_timeout: Option<Duration>,
+    ) -> Result<SharedSslStream<S>> {
+        SockRef::from(stream.get_ref()).set_nonblocking(true)?;
+        let fd = stream.get_ref().as_raw_fd();
+        Ok(SharedSslStream {
+            inner: Arc::new(Mutex::new(stream)),
+            fd,
+            read_timeout,
+            write_timeout,
+        })
+    }
+
+    /// Another handle to the same SSL session
+    pub fn try_clone(&self) -> SharedSslStream<S> {
+        SharedSslStream {
+            inner: Arc::clone(&self.inner),
+            fd: self.fd,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        }
+    }
+
+    /// Run `f` with the SSL session locked
+    pub fn with_stream<T, F: FnOnce(&mut SslStream<S>) -> T>(&self, f: F) -> T {
+        f(&mut self.inner.lock().unwrap())
+    }
+
+    /// Send the close_notify alert.  Best effort, the peer is not waited for
+    pub fn shutdown(&self) {
+        let _ = self.inner.lock().unwrap().shutdown();
+    }
+
+    /// Details of the TLS session
+    pub fn tls_info(&self) -> TlsInfo {
+        self.with_stream(|s| TlsInfo::from_ssl(s.ssl()))
+    }
+
+    /// The ALPN protocol the server selected
+    pub fn negotiated_protocol(&self) -> Option<Vec<u8>> {
+        self.with_stream(|s| s.ssl().selected_alpn_protocol().map(|p| p.to_vec()))
+    }
+
+    /// Was the session resumed instead of negotiated with a full handshake
+    pub fn session_reused(&self) -> bool {
+        self.with_stream(|s| s.ssl().session_reused())
+    }
+
+    /// Retry `op` until it does not need to wait for the socket, waiting outside of the lock
+    fn retry<T, F>(&self, timeout: Option<Duration>, mut op: F) -> Result<T>
+    where
+        F: FnMut(&mut SslStream<S>) -> ::std::result::Result<T, ssl::Error>,
+    {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let err = match op(&mut self.inner.lock().unwrap()) {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            let events = match err.code() {
+                ErrorCode::WANT_READ => libc::POLLIN,
+                ErrorCode::WANT_WRITE => libc::POLLOUT,
+                _ => match err.io_error() {
+                    Some(e) if e.kind() == ErrorKind::WouldBlock => libc::POLLIN,
+                    _ => return Err(ssl_error(err)),
+                },
+            };
+            socket::wait_ready(self.fd, events, deadline)?;
+        }
+    }
+}
+
+/// Converts an SSL error into an io::Error
+fn ssl_error(e: ssl::Error) -> Error {
+    match e.into_io_error() {
+        Ok(e) => e,
+        Err(e) => Error::other(format!("An SSL error occurred. ({})", e)),
+    }
+}
+
+impl<S> AsRawFd for SharedSslStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<S: Read + Write + AsFd + AsRawFd> Read for SharedSslStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let timeout = self.read_timeout;
+        self.retry(timeout, |s| match s.ssl_read(buf) {
+            Ok(n) => Ok(n),
+            // clean close from the peer
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => Ok(0),
+            // EOF without close_notify
+            Err(ref e) if e.code() == ErrorCode::SYSCALL && e.io_error().is_none() => Ok(0),
+            Err(e) => Err(e),
+        })
+    }
+}
+
+impl<S: Read + Write + AsFd + AsRawFd> Write for SharedSslStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let timeout = self.write_timeout;
+        self.retry(timeout, |s| s.ssl_write(buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // records are written to the socket by SSL_write, nothing is buffered
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+    use super::SharedSslStream;
+    use crate::net::test_support::test_acceptor;
+
+    /// SSL server which sends "hello" once it has received one line
+    fn start_server() -> u16 {
+        let acceptor = test_acceptor();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let stream = acceptor.accept(stream).unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reader.get_mut().write_all(b"hello\n").unwrap();
+            let mut rest = Vec::new();
+            let _ = reader.read_to_end(&mut rest);
+        });
+        port
+    }
+
+    #[test]
+    fn test_full_duplex() {
+        let port = start_server();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let stream = connector.build().connect("localhost", socket).unwrap();
+        let shared = SharedSslStream::new(stream, Some(Duration::from_millis(5_000)), None).unwrap();
+
+        // the reader blocks waiting for "hello" which the server only sends after our write
+        let mut reader = BufReader::new(shared.try_clone());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            tx.send(line).unwrap();
+        });
+        thread::sleep(Duration::from_millis(200));
+        let mut writer = shared.try_clone();
+        writer.write_all(b"ping\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(5_000)).unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let port = start_server();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let stream = connector.build().connect("localhost", socket).unwrap();
+        let mut shared =
+            SharedSslStream::new(stream, Some(Duration::from_millis(200)), None).unwrap();
+        let mut buf = [0; 16];
+        let e = shared.read(&mut buf).err().unwrap();
+        assert_eq!(e.kind(), ::std::io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,205 @@
+//! Helpers for the TLS tests, shared by both SSL backends: throwaway certificates, OpenSSL
+//! acceptors and a local TLS echo server. OpenSSL is a dev-dependency so they work with `rustls`
+//! as well.
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+
+use crate::net::config::Config;
+use crate::net::tls::TlsIdentity;
+
+/// Serial numbers of the issued certificates, the CAs use 1
+static SERIAL: AtomicU32 = AtomicU32::new(2);
+
+/// Acceptor with a throwaway self-signed certificate for localhost
+pub fn test_acceptor() -> SslAcceptor {
+    let rsa = Rsa::generate(2048).unwrap();
+    let key = PKey::from_rsa(rsa).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.build()
+}
+
+/// Throwaway CA certificate and key
+pub fn test_ca() -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "net-utils test CA").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    cert.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    (cert.build(), key)
+}
+
+/// Certificate signed by `ca` for the DNS names and IP addresses in `sans`
+pub fn test_cert(ca: &(X509, PKey<Private>), sans: &[&str]) -> (X509, PKey<Private>) {
+    let not_before = Asn1Time::days_from_now(0).unwrap();
+    let not_after = Asn1Time::days_from_now(1).unwrap();
+    issue(ca, sans, &not_before, &not_after)
+}
+
+/// Like `test_cert`, but valid from two days ago until yesterday
+pub fn expired_cert(ca: &(X509, PKey<Private>), sans: &[&str]) -> (X509, PKey<Private>) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let not_before = Asn1Time::from_unix((now - 2 * 86_400) as _).unwrap();
+    let not_after = Asn1Time::from_unix((now - 86_400) as _).unwrap();
+    issue(ca, sans, &not_before, &not_after)
+}
+
+fn issue(
+    ca: &(X509, PKey<Private>),
+    sans: &[&str],
+    not_before: &Asn1Time,
+    not_after: &Asn1Time,
+) -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", sans.first().cloned().unwrap_or("client")).unwrap();
+    let name = name.build();
+    let serial = SERIAL.fetch_add(1, Ordering::Relaxed);
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(ca.0.subject_name()).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(not_before).unwrap();
+    cert.set_not_after(not_after).unwrap();
+    if !sans.is_empty() {
+        let mut san = SubjectAlternativeName::new();
+        for name in sans {
+            if name.parse::<::std::net::IpAddr>().is_ok() {
+                san.ip(name);
+            } else {
+                san.dns(name);
+            }
+        }
+        let san = san.build(&cert.x509v3_context(Some(&ca.0), None)).unwrap();
+        cert.append_extension(san).unwrap();
+    }
+    cert.sign(&ca.1, MessageDigest::sha256()).unwrap();
+    (cert.build(), key)
+}
+
+/// Throwaway CA with a server certificate for localhost and 127.0.0.1 and a client certificate
+pub struct TestPki {
+    pub ca: (X509, PKey<Private>),
+    pub server: (X509, PKey<Private>),
+    pub client: (X509, PKey<Private>),
+}
+
+impl TestPki {
+    pub fn new() -> TestPki {
+        let ca = test_ca();
+        let server = test_cert(&ca, &["localhost", "127.0.0.1"]);
+        let client = test_cert(&ca, &["client"]);
+        TestPki { ca, server, client }
+    }
+
+    /// The client certificate and key as a `TlsIdentity`
+    pub fn client_identity(&self) -> TlsIdentity {
+        identity(&self.client)
+    }
+
+    /// Config to reach the echo server on `port` at 127.0.0.1 with the CA trusted, the client
+    /// certificate presented and verification on. `name` keeps the CA file of each test apart
+    pub fn config(&self, port: u16, name: &str) -> Config {
+        let mut cfg: Config = Default::default();
+        cfg.server = Some("127.0.0.1".to_string());
+        cfg.port = Some(port);
+        cfg.use_ssl = Some(true);
+        cfg.verify = Some(true);
+        cfg.ca_file = Some(ca_file(&self.ca.0, name));
+        cfg.tls_identity = Some(self.client_identity());
+        cfg.read_timeout = Some(Duration::from_millis(5_000));
+        cfg
+    }
+}
+
+/// `cert` and its key as a `TlsIdentity`
+pub fn identity(cert: &(X509, PKey<Private>)) -> TlsIdentity {
+    TlsIdentity::Pem {
+        certs: cert.0.to_pem().unwrap(),
+        key: cert.1.private_key_to_pem_pkcs8().unwrap(),
+        passphrase: None,
+    }
+}
+
+/// Writes `ca` to a PEM file in the temporary directory
+pub fn ca_file(ca: &X509, name: &str) -> PathBuf {
+    let file = env::temp_dir().join(format!("net-utils-ca-{}-{}.pem", process::id(), name));
+    fs::write(&file, ca.to_pem().unwrap()).unwrap();
+    file
+}
+
+/// TLS echo server on 127.0.0.1 presenting `cert`. With `client_ca` clients must present a
+/// certificate issued by it. Each connection is served by its own thread until it is closed.
+/// Returns the port
+pub fn echo_server(cert: &(X509, PKey<Private>), client_ca: Option<&X509>) -> u16 {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&cert.1).unwrap();
+    acceptor.set_certificate(&cert.0).unwrap();
+    if let Some(ca) = client_ca {
+        acceptor.cert_store_mut().add_cert(ca.clone()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = acceptor.build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let acceptor = acceptor.clone();
+        thread::spawn(move || if let Ok(mut stream) = acceptor.accept(stream) {
+            let mut buf = [0; 1024];
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 || stream.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+    });
+    port
+}
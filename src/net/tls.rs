@@ -0,0 +1,915 @@
+//! TLS settings and connection details which do not depend on the SSL library.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "ssl")]
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+#[cfg(feature = "ssl")]
+use openssl::hash::{hash, MessageDigest};
+#[cfg(feature = "ssl")]
+use openssl::pkcs12::Pkcs12;
+#[cfg(feature = "ssl")]
+use openssl::pkey::PKey;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslContextBuilder, SslRef, SslSession, SslVersion};
+#[cfg(feature = "ssl")]
+use openssl::x509::{X509, X509NameRef, X509Ref};
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslConnector as Connector;
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+use rustls::{ClientConfig as Connector, ClientConnection, HandshakeKind, ProtocolVersion};
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::config::Config;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use crate::net::conn;
+#[cfg(feature = "ssl")]
+use crate::net::conn::ssl_to_io;
+
+/// TLS protocol version
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.0
+    Tls10,
+    /// TLS 1.1
+    Tls11,
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            TlsVersion::Tls10 => "TLSv1",
+            TlsVersion::Tls11 => "TLSv1.1",
+            TlsVersion::Tls12 => "TLSv1.2",
+            TlsVersion::Tls13 => "TLSv1.3",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl TlsVersion {
+    /// The OpenSSL version constant
+    pub(crate) fn to_openssl(self) -> SslVersion {
+        match self {
+            TlsVersion::Tls10 => SslVersion::TLS1,
+            TlsVersion::Tls11 => SslVersion::TLS1_1,
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        }
+    }
+
+    /// Version from the OpenSSL constant, None for SSLv3 and unknown versions
+    pub(crate) fn from_openssl(version: SslVersion) -> Option<TlsVersion> {
+        if version == SslVersion::TLS1 {
+            Some(TlsVersion::Tls10)
+        } else if version == SslVersion::TLS1_1 {
+            Some(TlsVersion::Tls11)
+        } else if version == SslVersion::TLS1_2 {
+            Some(TlsVersion::Tls12)
+        } else if version == SslVersion::TLS1_3 {
+            Some(TlsVersion::Tls13)
+        } else {
+            None
+        }
+    }
+}
+
+/// ALPN protocol list in wire format: each name prefixed with its length. Names must be 1 to
+/// 255 bytes long and the list must not be empty
+pub(crate) fn alpn_wire_format(protocols: &[Vec<u8>]) -> Result<Vec<u8>> {
+    if protocols.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "alpn_protocols is empty"));
+    }
+    let mut wire = Vec::new();
+    for protocol in protocols {
+        if protocol.is_empty() || protocol.len() > 255 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("ALPN protocol of {} bytes, must be 1 to 255", protocol.len()),
+            ));
+        }
+        wire.push(protocol.len() as u8);
+        wire.extend_from_slice(protocol);
+    }
+    Ok(wire)
+}
+
+/// Fails if `Config::alpn_required` is set and the server did not select one of the offered
+/// protocols
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+pub(crate) fn check_alpn(config: &Config, selected: Option<&[u8]>) -> Result<()> {
+    if !config.alpn_required.unwrap_or(false) {
+        return Ok(());
+    }
+    let offered = config.alpn_protocols.as_deref().unwrap_or(&[]);
+    match selected {
+        Some(p) if offered.iter().any(|o| o.as_slice() == p) => Ok(()),
+        Some(p) => Err(Error::other(format!(
+            "server selected ALPN protocol {} which was not offered",
+            String::from_utf8_lossy(p)
+        ))),
+        None => Err(Error::other("server did not select an ALPN protocol")),
+    }
+}
+
+/// Details of an established TLS session
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsInfo {
+    /// Negotiated protocol version
+    pub version: Option<TlsVersion>,
+    /// Negotiated cipher suite, e.g. "TLS_AES_128_GCM_SHA256"
+    pub cipher: Option<String>,
+    /// Certificates the server presented, its own first
+    pub peer_certificates: Vec<CertificateInfo>,
+    /// ALPN protocol the server selected
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Was the session resumed instead of negotiated with a full handshake
+    pub session_reused: bool,
+    /// DER OCSP response stapled by the server. Only requested if `Config::ocsp_stapling` is set
+    pub ocsp_response: Option<Vec<u8>>,
+}
+
+/// A certificate presented by the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertificateInfo {
+    /// DER encoded certificate
+    pub der: Vec<u8>,
+    /// Subject, e.g. "CN=example.com, O=Example"
+    pub subject: String,
+    /// Issuer, in the same form as the subject
+    pub issuer: String,
+    /// DNS names in the subject alternative names
+    pub dns_names: Vec<String>,
+    /// IP addresses in the subject alternative names
+    pub ip_addresses: Vec<IpAddr>,
+    /// Start of the validity period
+    pub not_before: SystemTime,
+    /// End of the validity period
+    pub not_after: SystemTime,
+}
+
+impl CertificateInfo {
+    /// Time left until the certificate expires, None if it has expired
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.not_after.duration_since(SystemTime::now()).ok()
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl TlsInfo {
+    /// Details of the session in `ssl`
+    pub(crate) fn from_ssl(ssl: &SslRef) -> TlsInfo {
+        let peer_certificates = match ssl.peer_cert_chain() {
+            Some(chain) => chain.iter().filter_map(|c| CertificateInfo::from_x509(c).ok()).collect(),
+            None => ssl.peer_certificate()
+                .and_then(|c| CertificateInfo::from_x509(&c).ok())
+                .into_iter()
+                .collect(),
+        };
+        TlsInfo {
+            version: ssl.version2().and_then(TlsVersion::from_openssl),
+            cipher: ssl.current_cipher().map(|c| c.name().to_string()),
+            peer_certificates,
+            alpn_protocol: ssl.selected_alpn_protocol().map(|p| p.to_vec()),
+            session_reused: ssl.session_reused(),
+            ocsp_response: ssl.ocsp_status().map(|r| r.to_vec()),
+        }
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl CertificateInfo {
+    /// Parse the details of `cert`
+    pub(crate) fn from_x509(cert: &X509Ref) -> Result<CertificateInfo> {
+        let mut dns_names = Vec::new();
+        let mut ip_addresses = Vec::new();
+        for name in cert.subject_alt_names().iter().flatten() {
+            if let Some(dns) = name.dnsname() {
+                dns_names.push(dns.to_string());
+            }
+            match name.ipaddress() {
+                Some(ip) if ip.len() == 4 => {
+                    let mut octets = [0; 4];
+                    octets.copy_from_slice(ip);
+                    ip_addresses.push(IpAddr::from(octets));
+                }
+                Some(ip) if ip.len() == 16 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(ip);
+                    ip_addresses.push(IpAddr::from(octets));
+                }
+                _ => {}
+            }
+        }
+        Ok(CertificateInfo {
+            der: ssl_to_io(cert.to_der())?,
+            subject: name_to_string(cert.subject_name()),
+            issuer: name_to_string(cert.issuer_name()),
+            dns_names,
+            ip_addresses,
+            not_before: asn1_to_system_time(cert.not_before())?,
+            not_after: asn1_to_system_time(cert.not_after())?,
+        })
+    }
+}
+
+/// "CN=example.com, O=Example" form of a name
+#[cfg(feature = "ssl")]
+fn name_to_string(name: &X509NameRef) -> String {
+    let entries: Vec<String> = name.entries()
+        .map(|e| {
+            let key = e.object().nid().short_name().unwrap_or("?");
+            let value = e.data().to_string().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect();
+    entries.join(", ")
+}
+
+/// Convert a certificate time, relative to now as OpenSSL does not expose the epoch seconds
+#[cfg(feature = "ssl")]
+fn asn1_to_system_time(time: &Asn1TimeRef) -> Result<SystemTime> {
+    let now = SystemTime::now();
+    let diff = ssl_to_io(ssl_to_io(Asn1Time::days_from_now(0))?.diff(time))?;
+    let secs = i64::from(diff.days) * 86_400 + i64::from(diff.secs);
+    if secs >= 0 {
+        Ok(now + Duration::from_secs(secs as u64))
+    } else {
+        Ok(now - Duration::from_secs(secs.unsigned_abs()))
+    }
+}
+
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+impl TlsInfo {
+    /// Details of the rustls session in `conn`
+    pub(crate) fn from_rustls(conn: &ClientConnection) -> TlsInfo {
+        let version = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => Some(TlsVersion::Tls12),
+            Some(ProtocolVersion::TLSv1_3) => Some(TlsVersion::Tls13),
+            _ => None,
+        };
+        // IANA names, rustls prefixes the TLS 1.3 ones with "TLS13_"
+        let cipher = conn.negotiated_cipher_suite()
+            .and_then(|s| s.suite().as_str())
+            .map(|name| name.replacen("TLS13_", "TLS_", 1));
+        let peer_certificates = conn.peer_certificates()
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|c| CertificateInfo::from_der(c).ok())
+            .collect();
+        TlsInfo {
+            version,
+            cipher,
+            peer_certificates,
+            alpn_protocol: conn.alpn_protocol().map(|p| p.to_vec()),
+            session_reused: conn.handshake_kind() == Some(HandshakeKind::Resumed),
+            ocsp_response: None,
+        }
+    }
+}
+
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+impl CertificateInfo {
+    /// Parse the details of a DER certificate
+    pub(crate) fn from_der(der: &[u8]) -> Result<CertificateInfo> {
+        let invalid =
+            |e: String| Error::new(ErrorKind::InvalidData, format!("invalid certificate: {}", e));
+        let (_, cert) = X509Certificate::from_der(der).map_err(|e| invalid(e.to_string()))?;
+        let mut dns_names = Vec::new();
+        let mut ip_addresses = Vec::new();
+        let san = cert.subject_alternative_name().map_err(|e| invalid(e.to_string()))?;
+        for name in san.iter().flat_map(|s| s.value.general_names.iter()) {
+            match *name {
+                GeneralName::DNSName(dns) => dns_names.push(dns.to_string()),
+                GeneralName::IPAddress(ip) if ip.len() == 4 => {
+                    let mut octets = [0; 4];
+                    octets.copy_from_slice(ip);
+                    ip_addresses.push(IpAddr::from(octets));
+                }
+                GeneralName::IPAddress(ip) if ip.len() == 16 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(ip);
+                    ip_addresses.push(IpAddr::from(octets));
+                }
+                _ => {}
+            }
+        }
+        let validity = cert.validity();
+        Ok(CertificateInfo {
+            der: der.to_vec(),
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            dns_names,
+            ip_addresses,
+            not_before: epoch_to_system_time(validity.not_before.timestamp()),
+            not_after: epoch_to_system_time(validity.not_after.timestamp()),
+        })
+    }
+}
+
+/// Convert seconds since the epoch
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+fn epoch_to_system_time(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+/// Client TLS sessions (tickets and session IDs) of recent connections, keyed by endpoint.
+/// New connections to the same endpoint try to resume the session instead of doing a full
+/// handshake. Pools create one unless `Config::tls_session_cache` is set, setting it shares
+/// the cache between pools. OpenSSL only, rustls resumes sessions between the connections of a
+/// `TlsConnector`
+#[derive(Debug, Default)]
+pub struct TlsSessionCache {
+    /// DER encoded session by endpoint
+    sessions: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl TlsSessionCache {
+    /// New empty cache
+    pub fn new() -> TlsSessionCache {
+        Default::default()
+    }
+
+    /// Number of endpoints with a cached session
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Is the cache empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget all sessions, e.g. after the client certificate changed
+    pub fn clear(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+
+    /// The latest session for `endpoint`
+    #[cfg(feature = "ssl")]
+    pub(crate) fn get(&self, endpoint: &str) -> Option<SslSession> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(endpoint).and_then(|der| SslSession::from_der(der).ok())
+    }
+
+    /// Keep `session` for `endpoint`, replacing the previous one
+    #[cfg(feature = "ssl")]
+    pub(crate) fn put(&self, endpoint: &str, session: &SslSession) {
+        if let Ok(der) = session.to_der() {
+            self.sessions.lock().unwrap().insert(endpoint.to_string(), der);
+        }
+    }
+}
+
+/// Returns the passphrase of an encrypted private key, e.g. fetched from a vault. Called when
+/// the key is loaded
+pub type PassphraseCallback = Arc<dyn Fn() -> Result<Vec<u8>> + Send + Sync>;
+
+/// Client certificate, its chain and private key. Takes the place of
+/// `Config::certificate_file` and `Config::private_key_file`
+#[derive(Clone)]
+pub enum TlsIdentity {
+    /// PEM certificates, leaf first followed by the intermediates, and a PEM private key. The
+    /// passphrase is required if the key is encrypted
+    Pem {
+        /// Certificate chain
+        certs: Vec<u8>,
+        /// Private key, PKCS#8 or traditional format
+        key: Vec<u8>,
+        /// Passphrase for an encrypted key
+        passphrase: Option<PassphraseCallback>,
+    },
+    /// DER certificates, leaf first followed by the intermediates, and a DER private key
+    Der {
+        /// Certificate chain
+        certs: Vec<Vec<u8>>,
+        /// Private key
+        key: Vec<u8>,
+    },
+    /// PKCS#12 (.p12/.pfx) archive with the key, the certificate and optionally the chain
+    Pkcs12 {
+        /// Archive contents
+        der: Vec<u8>,
+        /// Archive password
+        password: String,
+    },
+}
+
+impl TlsIdentity {
+    /// PEM identity read from a certificate chain file and a key file
+    pub fn from_pem_files<P: AsRef<Path>>(
+        certs: P,
+        key: P,
+        passphrase: Option<PassphraseCallback>,
+    ) -> Result<TlsIdentity> {
+        Ok(TlsIdentity::Pem {
+            certs: fs::read(certs)?,
+            key: fs::read(key)?,
+            passphrase,
+        })
+    }
+
+    /// PKCS#12 identity read from a file
+    pub fn from_pkcs12_file<P: AsRef<Path>>(path: P, password: &str) -> Result<TlsIdentity> {
+        Ok(TlsIdentity::Pkcs12 {
+            der: fs::read(path)?,
+            password: password.to_string(),
+        })
+    }
+}
+
+/// Source of trusted CA certificates
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrustAnchor {
+    /// PEM bundle of one or more certificates
+    Pem(Vec<u8>),
+    /// A DER certificate
+    Der(Vec<u8>),
+    /// PEM bundle file
+    File(PathBuf),
+    /// Directory of certificates named by subject hash, as prepared by `openssl rehash`
+    Dir(PathBuf),
+}
+
+/// CA certificates to verify the server with. Used in addition to `Config::ca_file`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrustAnchors {
+    /// The CA bundles, certificates and directories
+    pub anchors: Vec<TrustAnchor>,
+    /// Trust the system's default CAs as well
+    pub system_roots: bool,
+}
+
+impl TrustAnchors {
+    /// Only the given anchors, without the system's CAs
+    pub fn new(anchors: Vec<TrustAnchor>) -> TrustAnchors {
+        TrustAnchors {
+            anchors,
+            system_roots: false,
+        }
+    }
+}
+
+impl Default for TrustAnchors {
+    fn default() -> TrustAnchors {
+        TrustAnchors {
+            anchors: Vec::new(),
+            system_roots: true,
+        }
+    }
+}
+
+/// Set the certificate, chain and key of `identity` on the context
+#[cfg(feature = "ssl")]
+pub(crate) fn set_identity(ctx: &mut SslContextBuilder, identity: &TlsIdentity) -> Result<()> {
+    let (mut certs, key) = match *identity {
+        TlsIdentity::Pem {
+            ref certs,
+            ref key,
+            ref passphrase,
+        } => {
+            let key = match *passphrase {
+                Some(ref passphrase) => private_key_with_passphrase(key, passphrase)?,
+                None => ssl_to_io(PKey::private_key_from_pem(key))?,
+            };
+            (ssl_to_io(X509::stack_from_pem(certs))?, key)
+        }
+        TlsIdentity::Der { ref certs, ref key } => {
+            let certs = certs.iter().map(|c| X509::from_der(c)).collect();
+            (ssl_to_io(certs)?, ssl_to_io(PKey::private_key_from_der(key))?)
+        }
+        TlsIdentity::Pkcs12 {
+            ref der,
+            ref password,
+        } => {
+            let parsed = ssl_to_io(Pkcs12::from_der(der).and_then(|p| p.parse2(password)))?;
+            let mut certs: Vec<X509> = parsed.cert.into_iter().collect();
+            certs.extend(parsed.ca.into_iter().flatten());
+            match parsed.pkey {
+                Some(key) => (certs, key),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "PKCS#12 archive has no private key",
+                    ))
+                }
+            }
+        }
+    };
+    if certs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "TLS identity has no certificate"));
+    }
+    let chain = certs.split_off(1);
+    ssl_to_io(ctx.set_certificate(&certs[0]))?;
+    for cert in chain {
+        ssl_to_io(ctx.add_extra_chain_cert(cert))?;
+    }
+    ssl_to_io(ctx.set_private_key(&key))?;
+    ssl_to_io(ctx.check_private_key())
+}
+
+/// Decrypt a PEM key with the passphrase from the callback
+#[cfg(feature = "ssl")]
+fn private_key_with_passphrase(
+    pem: &[u8],
+    passphrase: &PassphraseCallback,
+) -> Result<PKey<openssl::pkey::Private>> {
+    let mut failure = None;
+    let key = PKey::private_key_from_pem_callback(pem, |buf| {
+        let result = passphrase().and_then(|p| if p.len() > buf.len() {
+            Err(Error::new(ErrorKind::InvalidInput, "passphrase is too long"))
+        } else {
+            buf[..p.len()].copy_from_slice(&p);
+            Ok(p.len())
+        });
+        result.map_err(|e| {
+            failure = Some(e);
+            openssl::error::ErrorStack::get()
+        })
+    });
+    match failure {
+        Some(e) => Err(e),
+        None => ssl_to_io(key),
+    }
+}
+
+/// Add the CAs of `anchors` to the context's certificate store
+#[cfg(feature = "ssl")]
+pub(crate) fn add_trust_anchors(ctx: &mut SslContextBuilder, anchors: &TrustAnchors) -> Result<()> {
+    for anchor in &anchors.anchors {
+        match *anchor {
+            TrustAnchor::Pem(ref pem) => {
+                for cert in ssl_to_io(X509::stack_from_pem(pem))? {
+                    ssl_to_io(ctx.cert_store_mut().add_cert(cert))?;
+                }
+            }
+            TrustAnchor::Der(ref der) => {
+                let cert = ssl_to_io(X509::from_der(der))?;
+                ssl_to_io(ctx.cert_store_mut().add_cert(cert))?;
+            }
+            TrustAnchor::File(ref path) => ssl_to_io(ctx.set_ca_file(path))?,
+            TrustAnchor::Dir(ref path) => ssl_to_io(ctx.load_verify_locations(None, Some(path)))?,
+        }
+    }
+    Ok(())
+}
+
+/// SHA-256 pin of a server certificate or of its public key (SPKI). The connection is accepted
+/// if any certificate the server presents matches one of `Config::tls_pins`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsPin {
+    /// Hash of the DER certificate
+    Certificate([u8; 32]),
+    /// Hash of the DER SubjectPublicKeyInfo. Survives certificate renewal with the same key
+    Spki([u8; 32]),
+}
+
+impl fmt::Display for TlsPin {
+    /// "cert-sha256:" or "spki-sha256:" followed by the hash in hex
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (kind, digest) = match *self {
+            TlsPin::Certificate(ref d) => ("cert", d),
+            TlsPin::Spki(ref d) => ("spki", d),
+        };
+        write!(f, "{}-sha256:", kind)?;
+        for b in digest.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TlsPin {
+    type Err = Error;
+
+    /// Parses the `Display` form. The hex digits may be separated by colons
+    fn from_str(s: &str) -> Result<TlsPin> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid TLS pin {}", s));
+        let (kind, hex) = match s.find(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => return Err(invalid()),
+        };
+        let hex: Vec<u8> = hex.bytes().filter(|&b| b != b':').collect();
+        if hex.len() != 64 {
+            return Err(invalid());
+        }
+        let mut digest = [0; 32];
+        for (i, pair) in hex.chunks(2).enumerate() {
+            let pair = ::std::str::from_utf8(pair).map_err(|_| invalid())?;
+            digest[i] = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        match kind {
+            "cert-sha256" => Ok(TlsPin::Certificate(digest)),
+            "spki-sha256" => Ok(TlsPin::Spki(digest)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The certificate and SPKI pins of `cert`
+#[cfg(feature = "ssl")]
+pub(crate) fn pins_of(cert: &X509Ref) -> Result<(TlsPin, TlsPin)> {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&ssl_to_io(cert.digest(MessageDigest::sha256()))?);
+    let certificate = TlsPin::Certificate(digest);
+    let spki = ssl_to_io(cert.public_key().and_then(|k| k.public_key_to_der()))?;
+    digest.copy_from_slice(&ssl_to_io(hash(MessageDigest::sha256(), &spki))?);
+    Ok((certificate, TlsPin::Spki(digest)))
+}
+
+/// Does any of `certs` match one of `pins`
+#[cfg(feature = "ssl")]
+pub(crate) fn pins_match<'a, I: IntoIterator<Item = &'a X509Ref>>(certs: I, pins: &[TlsPin]) -> bool {
+    certs.into_iter().any(|cert| match pins_of(cert) {
+        Ok((c, s)) => pins.contains(&c) || pins.contains(&s),
+        Err(_) => false,
+    })
+}
+
+/// Error for a server whose certificates do not match `Config::tls_pins`, naming the pins of
+/// the server certificate
+#[cfg(feature = "ssl")]
+pub(crate) fn pin_mismatch(cert: &X509Ref) -> Error {
+    match pins_of(cert) {
+        Ok((c, s)) => Error::other(format!(
+            "TLS pin mismatch: the server presented {} ({})",
+            c, s
+        )),
+        Err(e) => e,
+    }
+}
+
+/// SSL connector (the client config with `rustls`) built once from the TLS settings of a
+/// `Config` and shared by the connections made with it, through `Config::tls_connector`. The
+/// connector is replaced atomically when the certificate, key or CA files change
+/// (`reload_if_changed`) or new material is pushed (`set_identity`, `set_trust_anchors`).
+/// Connections already open keep the connector they were made with
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+#[derive(Clone)]
+pub struct TlsConnector {
+    inner: Arc<ConnectorInner>,
+}
+
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+struct ConnectorInner {
+    /// settings the current connector was built from
+    config: Mutex<Config>,
+    connector: RwLock<Arc<Connector>>,
+    /// bumped on every rebuild
+    generation: AtomicUsize,
+    /// watched files with their modification time and size when the connector was built
+    files: Mutex<Vec<FileState>>,
+}
+
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+impl TlsConnector {
+    /// Build the connector for the TLS settings of `config`. Fails for invalid settings or
+    /// unreadable certificate, key or CA files
+    pub fn new(config: &Config) -> Result<TlsConnector> {
+        let mut config = config.clone();
+        config.tls_connector = None;
+        // rustls keeps the sessions in the connector
+        #[cfg(feature = "ssl")]
+        if config.tls_session_cache.is_none() {
+            config.tls_session_cache = Some(Arc::new(TlsSessionCache::new()));
+        }
+        let files = file_states(&config);
+        let connector = conn::ssl_connector(&config)?;
+        Ok(TlsConnector {
+            inner: Arc::new(ConnectorInner {
+                config: Mutex::new(config),
+                connector: RwLock::new(Arc::new(connector)),
+                generation: AtomicUsize::new(0),
+                files: Mutex::new(files),
+            }),
+        })
+    }
+
+    /// The current connector
+    pub(crate) fn get(&self) -> Arc<Connector> {
+        self.inner.connector.read().unwrap().clone()
+    }
+
+    /// Sessions of the connections made with this connector, which resume each other's
+    #[cfg(feature = "ssl")]
+    pub fn session_cache(&self) -> Arc<TlsSessionCache> {
+        let config = self.inner.config.lock().unwrap();
+        config.tls_session_cache.clone().unwrap()
+    }
+
+    /// Number of times the connector was rebuilt
+    pub fn generation(&self) -> usize {
+        self.inner.generation.load(Ordering::SeqCst)
+    }
+
+    /// Rebuild the connector from the files. The current one is kept if it fails, e.g. when
+    /// only one of the certificate and the key has been replaced yet
+    pub fn reload(&self) -> Result<()> {
+        self.rebuild(|_| {}, false).map(|_| ())
+    }
+
+    /// Rebuild the connector if a certificate, key or CA file changed since it was built.
+    /// Returns true if it was rebuilt
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        self.rebuild(|_| {}, true)
+    }
+
+    /// Replace the client certificate and key
+    pub fn set_identity(&self, identity: TlsIdentity) -> Result<()> {
+        self.rebuild(|config| config.tls_identity = Some(identity), false).map(|_| ())
+    }
+
+    /// Replace the trusted CAs
+    pub fn set_trust_anchors(&self, anchors: TrustAnchors) -> Result<()> {
+        self.rebuild(|config| config.trust_anchors = Some(anchors), false).map(|_| ())
+    }
+
+    /// Build a connector for the updated settings and swap it in, only if the files changed if
+    /// `if_changed` is set. Sessions negotiated with the old material are not resumed
+    fn rebuild<F: FnOnce(&mut Config)>(&self, update: F, if_changed: bool) -> Result<bool> {
+        // held throughout so concurrent reloads do not interleave
+        let mut current = self.inner.config.lock().unwrap();
+        let mut config = current.clone();
+        update(&mut config);
+        let files = file_states(&config);
+        if if_changed && files == *self.inner.files.lock().unwrap() {
+            return Ok(false);
+        }
+        let connector = conn::ssl_connector(&config)?;
+        *self.inner.connector.write().unwrap() = Arc::new(connector);
+        if let Some(ref cache) = config.tls_session_cache {
+            cache.clear();
+        }
+        *self.inner.files.lock().unwrap() = files;
+        *current = config;
+        let generation = self.inner.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("TLS connector rebuilt, generation {}", generation);
+        Ok(true)
+    }
+}
+
+/// A watched file with its modification time and size, None if it could not be read
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+type FileState = (PathBuf, Option<(SystemTime, u64)>);
+
+/// Modification time and size of the certificate, key and CA files and directories in `config`
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+fn file_states(config: &Config) -> Vec<FileState> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    if config.tls_identity.is_none() {
+        paths.extend(config.certificate_file.iter().cloned());
+        paths.extend(config.private_key_file.iter().cloned());
+    }
+    paths.extend(config.ca_file.iter().cloned());
+    for anchor in config.trust_anchors.iter().flat_map(|t| t.anchors.iter()) {
+        match *anchor {
+            TrustAnchor::File(ref path) | TrustAnchor::Dir(ref path) => paths.push(path.clone()),
+            _ => {}
+        }
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            let state = fs::metadata(&path).ok().and_then(|m| m.modified().ok().map(|t| (t, m.len())));
+            (path, state)
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "ssl"))]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::sync::Arc;
+
+    use openssl::pkcs12::Pkcs12;
+    use openssl::ssl::{SslConnector, SslMethod};
+    use openssl::stack::Stack;
+    use openssl::symm::Cipher;
+
+    use super::{pins_match, pins_of, set_identity, TlsIdentity, TlsPin};
+    use crate::net::test_support::{test_ca, test_cert};
+
+    fn load(identity: &TlsIdentity) -> ::std::io::Result<()> {
+        let mut ctx = SslConnector::builder(SslMethod::tls()).unwrap();
+        set_identity(&mut ctx, identity)
+    }
+
+    #[test]
+    fn test_pem_and_der_identity() {
+        let ca = test_ca();
+        let (cert, key) = test_cert(&ca, &["client"]);
+        let mut certs = cert.to_pem().unwrap();
+        certs.extend(ca.0.to_pem().unwrap());
+        assert!(load(&TlsIdentity::Pem {
+            certs: certs.clone(),
+            key: key.private_key_to_pem_pkcs8().unwrap(),
+            passphrase: None,
+        }).is_ok());
+        assert!(load(&TlsIdentity::Der {
+            certs: vec![cert.to_der().unwrap(), ca.0.to_der().unwrap()],
+            key: key.private_key_to_der().unwrap(),
+        }).is_ok());
+
+        // the key does not belong to the certificate
+        assert!(load(&TlsIdentity::Pem {
+            certs: certs,
+            key: ca.1.private_key_to_pem_pkcs8().unwrap(),
+            passphrase: None,
+        }).is_err());
+        assert!(load(&TlsIdentity::Der {
+            certs: vec![],
+            key: key.private_key_to_der().unwrap(),
+        }).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_pem_identity() {
+        let ca = test_ca();
+        let (cert, key) = test_cert(&ca, &["client"]);
+        let encrypted = key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_128_cbc(), b"secret")
+            .unwrap();
+        let identity = |passphrase: &'static [u8]| TlsIdentity::Pem {
+            certs: cert.to_pem().unwrap(),
+            key: encrypted.clone(),
+            passphrase: Some(Arc::new(move || Ok(passphrase.to_vec()))),
+        };
+        assert!(load(&identity(b"secret")).is_ok());
+        assert!(load(&identity(b"wrong")).is_err());
+
+        // errors of the callback are passed on
+        let failing = TlsIdentity::Pem {
+            certs: cert.to_pem().unwrap(),
+            key: encrypted,
+            passphrase: Some(Arc::new(|| Err(Error::new(ErrorKind::NotFound, "vault sealed")))),
+        };
+        assert_eq!(load(&failing).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_pkcs12_identity() {
+        let ca = test_ca();
+        let (cert, key) = test_cert(&ca, &["client"]);
+        let mut chain = Stack::new().unwrap();
+        chain.push(ca.0.clone()).unwrap();
+        let der = Pkcs12::builder()
+            .name("client")
+            .pkey(&key)
+            .cert(&cert)
+            .ca(chain)
+            .build2("password")
+            .unwrap()
+            .to_der()
+            .unwrap();
+        assert!(load(&TlsIdentity::Pkcs12 {
+            der: der.clone(),
+            password: "password".to_string(),
+        }).is_ok());
+        assert!(load(&TlsIdentity::Pkcs12 {
+            der,
+            password: "wrong".to_string(),
+        }).is_err());
+    }
+
+    #[test]
+    fn test_pins() {
+        let ca = test_ca();
+        let (cert, _) = test_cert(&ca, &["server"]);
+        let (cert_pin, spki_pin) = pins_of(&cert).unwrap();
+        assert_eq!(cert_pin.to_string().parse::<TlsPin>().unwrap(), cert_pin);
+        assert_eq!(spki_pin.to_string().parse::<TlsPin>().unwrap(), spki_pin);
+        assert!(spki_pin.to_string().starts_with("spki-sha256:"));
+        // openssl x509 -fingerprint style
+        let hex = cert.digest(openssl::hash::MessageDigest::sha256()).unwrap().iter()
+            .map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":");
+        assert_eq!(format!("cert-sha256:{}", hex).parse::<TlsPin>().unwrap(), cert_pin);
+        assert!("cert-sha256:abcd".parse::<TlsPin>().is_err());
+        assert!("md5:00".parse::<TlsPin>().is_err());
+
+        assert!(pins_match(vec![&*cert], &[spki_pin]));
+        assert!(pins_match(vec![&*ca.0, &*cert], &[cert_pin]));
+        let (other, _) = test_cert(&ca, &["server"]);
+        assert!(!pins_match(vec![&*other, &*ca.0], &[cert_pin, spki_pin]));
+    }
+}